@@ -0,0 +1,88 @@
+-- Tables of one network as of the first versioned release. `{suffix}` is empty on mainnet
+-- and `_<name>` on every other network. Every statement is idempotent so that databases
+-- created before versioned migrations are adopted as they are.
+
+create table if not exists did_documents{suffix} (
+    did text not null,
+    handle text not null,
+    signing_key text not null,
+    ckb_address text not null,
+    tx_hash text not null,
+    block_number text not null,
+    outpoint text not null primary key,
+    did_document jsonb not null,
+    cell_data text not null,
+    lock_script_hash text not null,
+    valid boolean default true,
+    created_at TIMESTAMPTZ not null,
+    consumed_tx text,
+    consumed_at TIMESTAMPTZ,
+    consumed_block_number text,
+    deployment text
+);
+
+-- columns added to tables created by an earlier create_table.sql
+alter table did_documents{suffix} add column if not exists consumed_block_number text;
+alter table did_documents{suffix} add column if not exists deployment text;
+
+create index if not exists idx_did_documents{suffix}_did on did_documents{suffix}(did);
+create index if not exists idx_did_documents{suffix}_ckb_address on did_documents{suffix}(ckb_address);
+create index if not exists idx_did_documents{suffix}_outpoint on did_documents{suffix}(outpoint);
+create index if not exists idx_did_documents{suffix}_valid on did_documents{suffix}(valid);
+create index if not exists idx_did_documents{suffix}_signing_key on did_documents{suffix}(signing_key);
+create index if not exists idx_did_documents{suffix}_created_at on did_documents{suffix}(created_at);
+create index if not exists idx_did_documents{suffix}_lock_script_hash on did_documents{suffix}(lock_script_hash);
+create index if not exists idx_did_documents{suffix}_block_number on did_documents{suffix}(block_number);
+create index if not exists idx_did_documents{suffix}_consumed_block_number on did_documents{suffix}(consumed_block_number);
+
+create table if not exists block_checkpoints{suffix} (
+    block_number text not null primary key,
+    block_hash text not null
+);
+
+create table if not exists did_pending{suffix} (
+    did text not null,
+    handle text not null,
+    signing_key text not null,
+    ckb_address text not null,
+    tx_hash text not null,
+    outpoint text not null primary key,
+    did_document jsonb not null,
+    cell_data text not null,
+    lock_script_hash text not null,
+    deployment text,
+    tx_status text not null,
+    created_at TIMESTAMPTZ not null default now()
+);
+
+create index if not exists idx_did_pending{suffix}_did on did_pending{suffix}(did);
+create index if not exists idx_did_pending{suffix}_ckb_address on did_pending{suffix}(ckb_address);
+create index if not exists idx_did_pending{suffix}_signing_key on did_pending{suffix}(signing_key);
+create index if not exists idx_did_pending{suffix}_handle on did_pending{suffix}(handle);
+create index if not exists idx_did_pending{suffix}_lock_script_hash on did_pending{suffix}(lock_script_hash);
+create index if not exists idx_did_pending{suffix}_tx_hash on did_pending{suffix}(tx_hash);
+
+create table if not exists did_rejected{suffix} (
+    tx_hash text not null,
+    output_index integer not null,
+    block_number text not null,
+    did text,
+    cell_data text,
+    reason text not null,
+    created_at TIMESTAMPTZ not null,
+    primary key (tx_hash, output_index)
+);
+
+create index if not exists idx_did_rejected{suffix}_did on did_rejected{suffix}(did);
+create index if not exists idx_did_rejected{suffix}_block_number on did_rejected{suffix}(block_number);
+create index if not exists idx_did_rejected{suffix}_created_at on did_rejected{suffix}(created_at);
+
+create table if not exists did_orphan_consumptions{suffix} (
+    outpoint text not null primary key,
+    consumed_tx text not null,
+    consumed_at TIMESTAMPTZ not null,
+    consumed_block_number text not null,
+    created_at TIMESTAMPTZ not null default now()
+);
+
+create index if not exists idx_did_orphan_consumptions{suffix}_consumed_block_number on did_orphan_consumptions{suffix}(consumed_block_number);
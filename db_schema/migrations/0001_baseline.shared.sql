@@ -0,0 +1,12 @@
+-- Tables shared by every network as of the first versioned release, idempotent like the
+-- network tables.
+
+create table if not exists indexer_state (
+    network text not null primary key,
+    block_number text not null,
+    block_hash text,
+    tip_block_number text,
+    updated_at TIMESTAMPTZ not null default now()
+);
+
+alter table indexer_state add column if not exists tip_block_number text;
@@ -0,0 +1,31 @@
+-- Block numbers were stored as 16 digit big-endian hex text and hashes, outpoints and cell
+-- data as hex text without `0x`.
+
+alter table did_documents{suffix}
+    alter column tx_hash type bytea using decode(tx_hash, 'hex'),
+    alter column block_number type bigint using ('x' || lpad(block_number, 16, '0'))::bit(64)::bigint,
+    alter column outpoint type bytea using decode(outpoint, 'hex'),
+    alter column cell_data type bytea using decode(cell_data, 'hex'),
+    alter column lock_script_hash type bytea using decode(lock_script_hash, 'hex'),
+    alter column consumed_tx type bytea using decode(consumed_tx, 'hex'),
+    alter column consumed_block_number type bigint using ('x' || lpad(consumed_block_number, 16, '0'))::bit(64)::bigint;
+
+alter table block_checkpoints{suffix}
+    alter column block_number type bigint using ('x' || lpad(block_number, 16, '0'))::bit(64)::bigint,
+    alter column block_hash type bytea using decode(block_hash, 'hex');
+
+alter table did_pending{suffix}
+    alter column tx_hash type bytea using decode(tx_hash, 'hex'),
+    alter column outpoint type bytea using decode(outpoint, 'hex'),
+    alter column cell_data type bytea using decode(cell_data, 'hex'),
+    alter column lock_script_hash type bytea using decode(lock_script_hash, 'hex');
+
+alter table did_rejected{suffix}
+    alter column tx_hash type bytea using decode(tx_hash, 'hex'),
+    alter column block_number type bigint using ('x' || lpad(block_number, 16, '0'))::bit(64)::bigint,
+    alter column cell_data type bytea using decode(cell_data, 'hex');
+
+alter table did_orphan_consumptions{suffix}
+    alter column outpoint type bytea using decode(outpoint, 'hex'),
+    alter column consumed_tx type bytea using decode(consumed_tx, 'hex'),
+    alter column consumed_block_number type bigint using ('x' || lpad(consumed_block_number, 16, '0'))::bit(64)::bigint;
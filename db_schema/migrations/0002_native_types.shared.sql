@@ -0,0 +1,7 @@
+-- Block numbers were stored as 16 digit big-endian hex text and hashes as hex text
+-- without `0x`.
+
+alter table indexer_state
+    alter column block_number type bigint using ('x' || lpad(block_number, 16, '0'))::bit(64)::bigint,
+    alter column block_hash type bytea using decode(block_hash, 'hex'),
+    alter column tip_block_number type bigint using ('x' || lpad(tip_block_number, 16, '0'))::bit(64)::bigint;
@@ -0,0 +1,29 @@
+-- The current document of every DID: its latest cell in `did_documents` that is not
+-- consumed. Kept up to date by the indexer in the transaction of every window.
+
+create table did_current{suffix} (
+    did text not null primary key,
+    handle text not null,
+    signing_key text not null,
+    ckb_address text not null,
+    tx_hash bytea not null,
+    block_number bigint not null,
+    outpoint bytea not null unique,
+    did_document jsonb not null,
+    cell_data bytea not null,
+    lock_script_hash bytea not null,
+    created_at TIMESTAMPTZ not null,
+    deployment text
+);
+
+create index idx_did_current{suffix}_ckb_address on did_current{suffix}(ckb_address);
+create index idx_did_current{suffix}_signing_key on did_current{suffix}(signing_key);
+create index idx_did_current{suffix}_handle on did_current{suffix}(handle);
+create index idx_did_current{suffix}_lock_script_hash on did_current{suffix}(lock_script_hash);
+create index idx_did_current{suffix}_created_at on did_current{suffix}(created_at);
+
+insert into did_current{suffix} (did, handle, signing_key, ckb_address, tx_hash, block_number, outpoint, did_document, cell_data, lock_script_hash, created_at, deployment)
+select distinct on (did) did, handle, signing_key, ckb_address, tx_hash, block_number, outpoint, did_document, cell_data, lock_script_hash, created_at, deployment
+from did_documents{suffix}
+where consumed_tx is null
+order by did, block_number desc, outpoint desc;
@@ -0,0 +1 @@
+-- Nothing shared changes.
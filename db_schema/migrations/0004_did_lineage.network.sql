@@ -0,0 +1,21 @@
+-- Links between the versions of a DID: a transaction consuming a DID cell and creating a
+-- cell of the same DID makes the new cell the `next_outpoint` of the consumed one, and the
+-- consumed cell the `prev_outpoint` of the new one.
+
+alter table did_documents{suffix}
+    add column prev_outpoint bytea,
+    add column next_outpoint bytea;
+
+update did_documents{suffix} n set prev_outpoint = p.outpoint
+from did_documents{suffix} p
+where p.did = n.did and p.consumed_tx = n.tx_hash;
+
+update did_documents{suffix} p set next_outpoint = n.outpoint
+from did_documents{suffix} n
+where n.did = p.did and n.tx_hash = p.consumed_tx;
+
+alter table did_current{suffix} add column prev_outpoint bytea;
+
+update did_current{suffix} c set prev_outpoint = d.prev_outpoint
+from did_documents{suffix} d
+where d.outpoint = c.outpoint;
@@ -1,44 +1,341 @@
-use web5_indexer::{RpcClient, create_pg_pool, did_monitor, get_pg_pool, init_db};
+use clap::{Args, Parser, Subcommand};
+use web5_indexer::{
+    DEFAULT_DATABASE_URL, IndexerConfig, Network, RpcClient, config, connect_pg_pool, get_pg_pool,
+    indexer_status, init_db, migrate, reindex, resolve_did, run_monitor, set_config,
+    set_indexer_state,
+};
+
+/// Index Web5 DID cells of CKB into Postgres and serve them over HTTP.
+///
+/// Flags override the environment variables of the same setting, see the README for the
+/// per-network `CKB_<NAME>_*` settings that have no flag.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// Postgres connection string.
+    #[arg(long, global = true, env = "DATABASE_URL", default_value = DEFAULT_DATABASE_URL)]
+    database_url: String,
+    /// Runs `run` when omitted.
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Index every enabled network and serve the HTTP API.
+    Run {
+        #[command(flatten)]
+        index: IndexArgs,
+        #[command(flatten)]
+        serve: ServeArgs,
+    },
+    /// Serve the HTTP API only, from a database filled by another process.
+    Serve(ServeArgs),
+    /// Index every enabled network without serving the HTTP API.
+    Index(IndexArgs),
+    /// Apply pending schema migrations to every configured network and exit.
+    Migrate,
+    /// Index the block range `[from, to)` of a network again.
+    Reindex {
+        /// Network whose blocks are indexed again.
+        #[arg(long)]
+        net: String,
+        /// First block to index again, decimal or `0x` hex.
+        #[arg(long, value_parser = parse_block_number)]
+        from: u64,
+        /// First block not indexed again, clamped to the blocks indexed so far.
+        #[arg(long, value_parser = parse_block_number)]
+        to: u64,
+        #[command(flatten)]
+        rpc: RpcArgs,
+    },
+    /// Move the indexer state of a network to a block, rolling back everything indexed at or
+    /// above it when rewinding.
+    SetState {
+        #[arg(long)]
+        net: String,
+        /// Block to index next, decimal or `0x` hex.
+        #[arg(long, value_parser = parse_block_number)]
+        block: u64,
+    },
+    /// Print the indexer state of every network, or of `--net`, as JSON lines.
+    Status {
+        #[arg(long)]
+        net: Option<String>,
+    },
+    /// Print the current document of a DID as JSON.
+    Resolve {
+        /// DID as stored in the `did` column, without a method prefix.
+        did: String,
+        #[arg(long, default_value = "mainnet")]
+        net: String,
+    },
+}
+
+#[derive(Args)]
+struct IndexArgs {
+    /// Index only these networks, overrides `CKB_<NAME>_INDEXER_ENABLED`. Repeat or comma
+    /// separate to index several.
+    #[arg(long, value_delimiter = ',')]
+    net: Vec<String>,
+    /// Longest wait between two indexing runs of every network,
+    /// overrides `*_POLL_INTERVAL_SECS`.
+    #[arg(long)]
+    poll_interval_secs: Option<u64>,
+    /// Blocks that must be built on top of a block before it is indexed, on every network,
+    /// overrides `*_CONFIRMATIONS`.
+    #[arg(long)]
+    confirmations: Option<u64>,
+    /// Exit the process on any panic instead of restarting the panicking indexer task.
+    #[arg(long, env = "ALLOW_EXIT_ON_PANIC")]
+    allow_exit_on_panic: bool,
+    #[command(flatten)]
+    rpc: RpcArgs,
+}
+
+#[derive(Args, Default)]
+struct RpcArgs {
+    /// Blocks fetched, parsed and committed per step, overrides `INDEXER_WINDOW_SIZE`.
+    #[arg(long)]
+    window_size: Option<u64>,
+    /// RPC requests in flight at once, overrides `INDEXER_RPC_CONCURRENCY`.
+    #[arg(long)]
+    rpc_concurrency: Option<usize>,
+    /// Calls per JSON-RPC batch request, overrides `INDEXER_RPC_BATCH_SIZE`.
+    #[arg(long)]
+    rpc_batch_size: Option<usize>,
+    /// Attempts per RPC call and database commit, `0` retries forever, overrides
+    /// `INDEXER_RPC_MAX_ATTEMPTS`.
+    #[arg(long)]
+    rpc_max_attempts: Option<u32>,
+    /// Blocks an endpoint may trail the best one before it is taken out of rotation,
+    /// overrides `INDEXER_RPC_MAX_LAG`.
+    #[arg(long)]
+    rpc_max_lag: Option<u64>,
+    /// Load batches of at least this many new DID cells with binary COPY, `0` never does,
+    /// overrides `INDEXER_COPY_MIN_ROWS`.
+    #[arg(long)]
+    copy_min_rows: Option<usize>,
+}
+
+#[derive(Args)]
+struct ServeArgs {
+    /// Port of the HTTP API.
+    #[arg(long, env = "HTTP_PORT", default_value_t = 8000)]
+    http_port: u16,
+}
+
+impl RpcArgs {
+    fn apply(&self, config: &mut IndexerConfig) {
+        if let Some(window_size) = self.window_size {
+            config.window_size = window_size;
+        }
+        if let Some(rpc_concurrency) = self.rpc_concurrency {
+            config.rpc_concurrency = rpc_concurrency;
+        }
+        if let Some(rpc_batch_size) = self.rpc_batch_size {
+            config.rpc_batch_size = rpc_batch_size;
+        }
+        if let Some(max_attempts) = self.rpc_max_attempts {
+            config.rpc_retry.max_attempts = max_attempts;
+        }
+        if let Some(rpc_max_lag) = self.rpc_max_lag {
+            config.rpc_max_lag = rpc_max_lag;
+        }
+        if let Some(copy_min_rows) = self.copy_min_rows {
+            config.copy_min_rows = copy_min_rows;
+        }
+    }
+}
+
+impl IndexArgs {
+    fn apply(&self, config: &mut IndexerConfig) -> Result<(), String> {
+        self.rpc.apply(config);
+        for name in &self.net {
+            if !networks_mut(config).any(|network| &network.name == name) {
+                return Err(format!("unknown network {}", name));
+            }
+        }
+        for network in networks_mut(config) {
+            if !self.net.is_empty() {
+                network.enabled = self.net.contains(&network.name);
+            }
+            if let Some(poll_interval_secs) = self.poll_interval_secs {
+                network.poll_interval_secs = poll_interval_secs;
+            }
+            if let Some(confirmations) = self.confirmations {
+                network.confirmations = confirmations;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn networks_mut(
+    config: &mut IndexerConfig,
+) -> impl Iterator<Item = &mut web5_indexer::NetworkConfig> {
+    [&mut config.mainnet, &mut config.testnet]
+        .into_iter()
+        .chain(config.custom.iter_mut())
+}
 
 fn main() {
     env_logger::init();
-    if std::env::var("ALLOW_EXIT_ON_PANIC")
-        .unwrap_or_default()
-        .parse()
-        .unwrap_or(true)
-    {
-        std::panic::set_hook(Box::new(|info| {
-            log::error!("Panic occurred: {:?}", info);
-            std::process::exit(1);
-        }));
+    let mut cli = Cli::parse();
+    if cli.command.is_none() {
+        // keep the environment defaults of `run`, such as `HTTP_PORT`
+        cli = Cli::parse_from(std::env::args().chain(["run".to_string()]));
+    }
+    if let Err(e) = run_command(cli) {
+        log::error!("{}", e);
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
+}
 
-    let rt = tokio::runtime::Runtime::new().unwrap();
+fn run_command(cli: Cli) -> Result<(), String> {
+    let mut indexer_config = IndexerConfig::from_env();
+    let command = cli.command.expect("defaults to run");
+    match &command {
+        Command::Run { index, .. } | Command::Index(index) => {
+            index.apply(&mut indexer_config)?;
+            // a panicking indexer task is restarted on its own, exiting would take the API
+            // down too
+            let exit_on_panic = index.allow_exit_on_panic;
+            std::panic::set_hook(Box::new(move |info| {
+                log::error!("Panic occurred: {:?}", info);
+                if exit_on_panic {
+                    std::process::exit(1);
+                }
+            }));
+        }
+        Command::Reindex { rpc, .. } => rpc.apply(&mut indexer_config),
+        _ => {}
+    }
+    set_config(indexer_config);
 
+    let rt = tokio::runtime::Runtime::new().unwrap();
     rt.block_on(async move {
-        create_pg_pool().await;
+        connect_pg_pool(&cli.database_url).await;
         let pool = get_pg_pool();
-        init_db(&pool).await;
-
-        tokio::spawn(async move {
-            let rpc = RpcClient::new();
-            loop {
-                did_monitor(&rpc).await;
-                tokio::time::sleep(std::time::Duration::from_secs(10 * 60)).await;
+        match command {
+            Command::Run { serve, .. } => {
+                init_db(pool).await;
+                start_indexers();
+                http_server(serve.http_port).await;
+            }
+            Command::Serve(serve) => http_server(serve.http_port).await,
+            Command::Index(_) => {
+                init_db(pool).await;
+                start_indexers();
+                std::future::pending::<()>().await;
+            }
+            Command::Migrate => {
+                let applied = migrate(pool)
+                    .await
+                    .map_err(|e| format!("Migration failed: {}", e))?;
+                for migration in &applied {
+                    println!(
+                        "{} {} {}",
+                        migration.network.map_or("shared", |net| net.name()),
+                        migration.version,
+                        migration.description
+                    );
+                }
+                if applied.is_empty() {
+                    println!("Database is up to date");
+                }
+            }
+            Command::Reindex { net, from, to, .. } => {
+                let net = find_network(&net)?;
+                init_db(pool).await;
+                reindex(&RpcClient::new(), net, from.into(), to.into())
+                    .await
+                    .map_err(|e| format!("Re-indexing failed: {}", e))?;
+            }
+            Command::SetState { net, block } => {
+                let net = find_network(&net)?;
+                init_db(pool).await;
+                set_indexer_state(pool, net, block.into())
+                    .await
+                    .map_err(|e| format!("Failed to set indexer state: {}", e))?;
+                println!(
+                    "Indexer state of {} set to block number {}",
+                    net.name(),
+                    block
+                );
+            }
+            Command::Status { net } => {
+                let networks = match net {
+                    Some(net) => vec![find_network(&net)?],
+                    None => config().networks().collect(),
+                };
+                for net in networks {
+                    let status = indexer_status(pool, net)
+                        .await
+                        .map_err(|e| format!("Failed to fetch indexer state: {}", e))?;
+                    println!("{}", status);
+                }
+            }
+            Command::Resolve { did, net } => {
+                let net = find_network(&net)?;
+                match resolve_did(pool, net, &did)
+                    .await
+                    .map_err(|e| format!("Failed to resolve {}: {}", did, e))?
+                {
+                    Some(record) => println!("{}", record),
+                    None => return Err(format!("{} has no live cell on {}", did, net.name())),
+                }
             }
-        });
+        }
+        Ok(())
+    })
+}
+
+fn find_network(name: &str) -> Result<Network, String> {
+    config()
+        .find_network(name)
+        .ok_or_else(|| format!("unknown network {}", name))
+}
+
+fn parse_block_number(raw: &str) -> Result<u64, String> {
+    match raw.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => raw.parse(),
+    }
+    .map_err(|e| e.to_string())
+}
 
-        http_server().await;
-    });
+fn start_indexers() {
+    for net in config().networks() {
+        if config().network(net).enabled {
+            tokio::spawn(supervise_monitor(net));
+        } else {
+            log::info!("Indexing of {:?} is disabled", net);
+        }
+    }
+}
+
+/// Run the indexer of `net`, restarting it after a panic.
+async fn supervise_monitor(net: Network) {
+    loop {
+        match tokio::spawn(run_monitor(RpcClient::new(), net)).await {
+            Err(e) if e.is_panic() => {
+                log::error!("Indexer of {:?} panicked, restarting in 10s", net);
+                tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+            }
+            _ => return,
+        }
+    }
 }
 
-async fn http_server() {
+async fn http_server(http_port: u16) {
     use salvo::{
         Listener, Router, Server, Service, conn::TcpListener, cors::AllowOrigin, cors::Cors,
     };
     use web5_indexer::{
         did_from_addr, did_from_handle, did_from_id, did_from_lock_script_hash,
-        did_from_signing_key,
+        did_from_signing_key, did_history, did_rejected, indexer_state,
     };
 
     use salvo::http::Method;
@@ -53,10 +350,12 @@ async fn http_server() {
         .push(Router::with_path("did_from_address").get(did_from_addr))
         .push(Router::with_path("did_from_signing_key").get(did_from_signing_key))
         .push(Router::with_path("did_from_handle").get(did_from_handle))
-        .push(Router::with_path("did_from_lock_script_hash").get(did_from_lock_script_hash));
+        .push(Router::with_path("did_from_lock_script_hash").get(did_from_lock_script_hash))
+        .push(Router::with_path("did_history").get(did_history))
+        .push(Router::with_path("did_rejected").get(did_rejected))
+        .push(Router::with_path("indexer_state").get(indexer_state));
 
     let service = Service::new(router).hoop(cors);
-    let http_port = std::env::var("HTTP_PORT").unwrap_or("8000".to_string());
     let listener = TcpListener::new(format!("0.0.0.0:{}", http_port))
         .bind()
         .await;
@@ -0,0 +1,450 @@
+use crate::{
+    Network, RetryPolicy,
+    rpc_client::{MAINNET_CODE_HASH, TESTNET_CODE_HASH},
+};
+use ckb_jsonrpc_types::{JsonBytes, Script, ScriptHashType};
+use ckb_sdk::NetworkType;
+use ckb_types::H256;
+use reqwest::Url;
+
+use std::{str::FromStr, sync::OnceLock, time::Duration};
+
+/// Tunables of the indexing pipeline.
+///
+/// Read from the environment the first time they are needed, unless a value was installed
+/// before with [`set_config`].
+#[derive(Clone, Debug)]
+pub struct IndexerConfig {
+    /// How many blocks are fetched, parsed and committed together, `INDEXER_WINDOW_SIZE`.
+    pub window_size: u64,
+    /// How many RPC requests are in flight at once while fetching a window,
+    /// `INDEXER_RPC_CONCURRENCY`.
+    pub rpc_concurrency: usize,
+    /// How many calls are sent in one JSON-RPC batch request, `INDEXER_RPC_BATCH_SIZE`.
+    pub rpc_batch_size: usize,
+    /// Retries of failed RPC calls, `INDEXER_RPC_MAX_ATTEMPTS`, `INDEXER_RPC_BACKOFF_MS` and
+    /// `INDEXER_RPC_MAX_BACKOFF_MS`.
+    pub rpc_retry: RetryPolicy,
+    /// How many blocks an RPC endpoint's indexer may fall behind the best endpoint before it
+    /// is taken out of rotation, `INDEXER_RPC_MAX_LAG`.
+    pub rpc_max_lag: u64,
+    /// Batches of at least this many new DID cells are loaded with binary `COPY` instead of
+    /// multi-row inserts, `INDEXER_COPY_MIN_ROWS`. `0` never uses `COPY`.
+    pub copy_min_rows: usize,
+    pub mainnet: NetworkConfig,
+    pub testnet: NetworkConfig,
+    /// Further networks such as a local devnet, listed by name in `INDEXER_NETWORKS`.
+    pub custom: Vec<NetworkConfig>,
+}
+
+/// Settings of the indexer task of one network, read from `CKB_<NAME>_*`, for example
+/// `CKB_MAINNET_*` or `CKB_TESTNET_*`.
+#[derive(Clone, Debug)]
+pub struct NetworkConfig {
+    /// Value of the `net` query parameter, also the suffix of the network's tables
+    /// for custom networks.
+    pub name: String,
+    /// RPC endpoints, `*_RPC_URL` as a comma separated list.
+    pub rpc_urls: Vec<Url>,
+    /// Whether the network is indexed at all, `*_INDEXER_ENABLED`.
+    pub enabled: bool,
+    /// Seconds between two indexing runs when no new tip was announced,
+    /// `*_POLL_INTERVAL_SECS`, falling back to `INDEXER_POLL_INTERVAL_SECS`.
+    pub poll_interval_secs: u64,
+    /// CKB TCP RPC address used to subscribe to new tips, `*_SUBSCRIBE_ADDR`.
+    pub subscribe_addr: Option<String>,
+    /// Blocks that must be built on top of a block before it is indexed,
+    /// `*_CONFIRMATIONS`.
+    pub confirmations: u64,
+    /// DID type scripts indexed on this network, `*_DID_DEPLOYMENTS`, or a single one from
+    /// `*_DID_CODE_HASH`.
+    pub deployments: Vec<DidDeployment>,
+    /// Which address prefix is used for `ckb_address`, `*_ADDRESS_PREFIX` as `ckb` or `ckt`.
+    pub address_type: NetworkType,
+}
+
+/// One deployment of the DID type script.
+///
+/// Written as `name=code_hash[:hash_type][@start[..end]]` in `*_DID_DEPLOYMENTS`, where
+/// `hash_type` is `type` (the default), `data`, `data1` or `data2`.
+#[derive(Clone, Debug)]
+pub struct DidDeployment {
+    /// Recorded in the `deployment` column of every row indexed from this deployment.
+    pub name: String,
+    pub code_hash: H256,
+    pub hash_type: ScriptHashType,
+    /// First block whose new DID cells are indexed from this deployment.
+    pub start: u64,
+    /// First block whose new DID cells are not indexed anymore. Consumptions of its cells
+    /// are tracked regardless.
+    pub end: Option<u64>,
+}
+
+impl DidDeployment {
+    pub fn new(name: &str, code_hash: H256) -> Self {
+        Self {
+            name: name.to_string(),
+            code_hash,
+            hash_type: ScriptHashType::Type,
+            start: 0,
+            end: None,
+        }
+    }
+
+    pub fn script(&self, args: JsonBytes) -> Script {
+        Script {
+            code_hash: self.code_hash.clone(),
+            hash_type: self.hash_type,
+            args,
+        }
+    }
+
+    /// Whether DID cells created at `block_number` are indexed from this deployment.
+    pub fn is_active(&self, block_number: u64) -> bool {
+        self.start <= block_number && self.end.is_none_or(|end| block_number < end)
+    }
+
+    /// Whether `script` is an instance of this deployment, whatever its args.
+    pub fn matches(&self, script: &Script) -> bool {
+        script.code_hash == self.code_hash && script.hash_type == self.hash_type
+    }
+}
+
+impl FromStr for DidDeployment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, rest) = s
+            .split_once('=')
+            .filter(|(name, _)| !name.is_empty())
+            .ok_or_else(|| format!("{:?} has no deployment name", s))?;
+        let (script, range) = rest.split_once('@').unwrap_or((rest, ""));
+        let (code_hash, hash_type) = script.split_once(':').unwrap_or((script, "type"));
+        let code_hash = H256::from_str(code_hash.trim_start_matches("0x"))
+            .map_err(|e| format!("{:?} has an invalid code hash: {}", s, e))?;
+        let hash_type = match hash_type {
+            "type" => ScriptHashType::Type,
+            "data" => ScriptHashType::Data,
+            "data1" => ScriptHashType::Data1,
+            "data2" => ScriptHashType::Data2,
+            _ => return Err(format!("{:?} has an unknown hash type", s)),
+        };
+        let (start, end) = range.split_once("..").unwrap_or((range, ""));
+        let parse_height = |height: &str| {
+            height
+                .parse::<u64>()
+                .map_err(|e| format!("{:?} has an invalid block number: {}", s, e))
+        };
+        Ok(Self {
+            name: name.to_string(),
+            code_hash,
+            hash_type,
+            start: if start.is_empty() {
+                0
+            } else {
+                parse_height(start)?
+            },
+            end: if end.is_empty() {
+                None
+            } else {
+                Some(parse_height(end)?)
+            },
+        })
+    }
+}
+
+impl Default for IndexerConfig {
+    fn default() -> Self {
+        Self {
+            window_size: 10000,
+            rpc_concurrency: 16,
+            rpc_batch_size: 50,
+            rpc_retry: RetryPolicy::default(),
+            rpc_max_lag: 20,
+            copy_min_rows: 0,
+            mainnet: NetworkConfig::new(
+                "mainnet",
+                "https://mainnet.ckb.dev",
+                vec![DidDeployment::new("v1", MAINNET_CODE_HASH.clone())],
+                NetworkType::Mainnet,
+            ),
+            testnet: NetworkConfig::new(
+                "testnet",
+                "https://testnet.ckb.dev",
+                vec![DidDeployment::new("v1", TESTNET_CODE_HASH.clone())],
+                NetworkType::Testnet,
+            ),
+            custom: Vec::new(),
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub fn new(
+        name: &str,
+        rpc_url: &str,
+        deployments: Vec<DidDeployment>,
+        address_type: NetworkType,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            rpc_urls: vec![Url::parse(rpc_url).unwrap()],
+            enabled: true,
+            poll_interval_secs: 10 * 60,
+            subscribe_addr: None,
+            confirmations: 24,
+            deployments,
+            address_type,
+        }
+    }
+
+    fn from_env(default: Self) -> Self {
+        let prefix = format!("CKB_{}", default.name.to_uppercase());
+        let rpc_urls = std::env::var(format!("{prefix}_RPC_URL"))
+            .ok()
+            .map(|urls| {
+                urls.split(',')
+                    .filter_map(|url| Url::parse(url.trim()).ok())
+                    .collect::<Vec<_>>()
+            })
+            .filter(|urls| !urls.is_empty())
+            .unwrap_or(default.rpc_urls);
+        let poll_interval_secs = env_or("INDEXER_POLL_INTERVAL_SECS", default.poll_interval_secs);
+        Self {
+            rpc_urls,
+            enabled: env_or(&format!("{prefix}_INDEXER_ENABLED"), default.enabled),
+            poll_interval_secs: env_or(&format!("{prefix}_POLL_INTERVAL_SECS"), poll_interval_secs),
+            subscribe_addr: std::env::var(format!("{prefix}_SUBSCRIBE_ADDR")).ok(),
+            confirmations: env_or(&format!("{prefix}_CONFIRMATIONS"), default.confirmations),
+            deployments: deployments_from_env(&prefix).unwrap_or(default.deployments),
+            address_type: std::env::var(format!("{prefix}_ADDRESS_PREFIX"))
+                .ok()
+                .and_then(|prefix| NetworkType::from_prefix(&prefix))
+                .unwrap_or(default.address_type),
+            name: default.name,
+        }
+    }
+}
+
+impl IndexerConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            window_size: env_or("INDEXER_WINDOW_SIZE", default.window_size),
+            rpc_concurrency: env_or("INDEXER_RPC_CONCURRENCY", default.rpc_concurrency),
+            rpc_batch_size: env_or("INDEXER_RPC_BATCH_SIZE", default.rpc_batch_size),
+            rpc_retry: RetryPolicy {
+                max_attempts: env_or("INDEXER_RPC_MAX_ATTEMPTS", default.rpc_retry.max_attempts),
+                initial_backoff: Duration::from_millis(env_or(
+                    "INDEXER_RPC_BACKOFF_MS",
+                    default.rpc_retry.initial_backoff.as_millis() as u64,
+                )),
+                max_backoff: Duration::from_millis(env_or(
+                    "INDEXER_RPC_MAX_BACKOFF_MS",
+                    default.rpc_retry.max_backoff.as_millis() as u64,
+                )),
+            },
+            rpc_max_lag: env_or("INDEXER_RPC_MAX_LAG", default.rpc_max_lag),
+            copy_min_rows: env_or("INDEXER_COPY_MIN_ROWS", default.copy_min_rows),
+            mainnet: NetworkConfig::from_env(default.mainnet),
+            testnet: NetworkConfig::from_env(default.testnet),
+            custom: std::env::var("INDEXER_NETWORKS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .filter_map(custom_network_from_env)
+                .collect(),
+        }
+    }
+
+    /// # Panics
+    ///
+    /// If `net` is a custom network that is not configured.
+    pub fn network(&self, net: Network) -> &NetworkConfig {
+        match net {
+            Network::Mainnet => &self.mainnet,
+            Network::Testnet => &self.testnet,
+            Network::Custom(name) => self
+                .custom
+                .iter()
+                .find(|config| config.name == name)
+                .unwrap_or_else(|| panic!("network {} is not configured", name)),
+        }
+    }
+
+    /// Look up a network by the name used in the `net` query parameter.
+    pub fn find_network(&'static self, name: &str) -> Option<Network> {
+        match name {
+            "mainnet" | "Mainnet" => Some(Network::Mainnet),
+            "testnet" | "Testnet" => Some(Network::Testnet),
+            _ => self
+                .custom
+                .iter()
+                .find(|config| config.name == name)
+                .map(|config| Network::Custom(&config.name)),
+        }
+    }
+
+    /// Mainnet, testnet and every custom network.
+    pub fn networks(&'static self) -> impl Iterator<Item = Network> {
+        [Network::Mainnet, Network::Testnet].into_iter().chain(
+            self.custom
+                .iter()
+                .map(|config| Network::Custom(&config.name)),
+        )
+    }
+}
+
+/// A custom network needs its DID deployments, everything else has a default suited to a
+/// local devnet.
+fn custom_network_from_env(name: &str) -> Option<NetworkConfig> {
+    let valid = name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if !valid || name == "mainnet" || name == "testnet" {
+        log::error!(
+            "Ignoring network {:?}, names other than mainnet and testnet are lowercase letters, digits and underscores",
+            name
+        );
+        return None;
+    }
+    let mut default = NetworkConfig::new(
+        name,
+        "http://127.0.0.1:8114",
+        Vec::new(),
+        NetworkType::Testnet,
+    );
+    default.confirmations = 0;
+    let config = NetworkConfig::from_env(default);
+    if config.deployments.is_empty() {
+        log::error!(
+            "Ignoring network {}, neither CKB_{}_DID_DEPLOYMENTS nor CKB_{1}_DID_CODE_HASH is set",
+            name,
+            name.to_uppercase()
+        );
+        return None;
+    }
+    Some(config)
+}
+
+/// `*_DID_DEPLOYMENTS` if it is set, otherwise a single deployment from `*_DID_CODE_HASH`.
+fn deployments_from_env(prefix: &str) -> Option<Vec<DidDeployment>> {
+    if let Ok(deployments) = std::env::var(format!("{prefix}_DID_DEPLOYMENTS")) {
+        let parsed = deployments
+            .split(',')
+            .map(str::trim)
+            .filter(|deployment| !deployment.is_empty())
+            .map(DidDeployment::from_str)
+            .collect::<Result<Vec<_>, _>>();
+        match parsed {
+            Ok(parsed) if !parsed.is_empty() => return Some(parsed),
+            Ok(_) => log::error!("Ignoring {}_DID_DEPLOYMENTS, it is empty", prefix),
+            Err(e) => log::error!("Ignoring {}_DID_DEPLOYMENTS, {}", prefix, e),
+        }
+    }
+    std::env::var(format!("{prefix}_DID_CODE_HASH"))
+        .ok()
+        .and_then(|hash| H256::from_str(hash.trim_start_matches("0x")).ok())
+        .map(|code_hash| vec![DidDeployment::new("v1", code_hash)])
+}
+
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+static CONFIG: OnceLock<IndexerConfig> = OnceLock::new();
+
+pub fn config() -> &'static IndexerConfig {
+    CONFIG.get_or_init(IndexerConfig::from_env)
+}
+
+pub fn set_config(config: IndexerConfig) {
+    CONFIG.set(config).expect("CONFIG already set");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODE_HASH: &str = "0x4a06164dc34dccade5afe3e847a97b6db743e79f5477fa3295acf02849c5984a";
+
+    fn parse(s: &str) -> Result<DidDeployment, String> {
+        s.parse()
+    }
+
+    #[test]
+    fn deployment_defaults() {
+        let deployment = parse(&format!("v1={}", CODE_HASH)).unwrap();
+        assert_eq!(deployment.name, "v1");
+        assert_eq!(
+            deployment.code_hash,
+            H256::from_str(&CODE_HASH[2..]).unwrap()
+        );
+        assert_eq!(deployment.hash_type, ScriptHashType::Type);
+        assert_eq!((deployment.start, deployment.end), (0, None));
+        // the `0x` prefix is optional
+        let deployment = parse(&format!("v1={}", &CODE_HASH[2..])).unwrap();
+        assert_eq!(
+            deployment.code_hash,
+            H256::from_str(&CODE_HASH[2..]).unwrap()
+        );
+    }
+
+    #[test]
+    fn deployment_hash_types() {
+        for (name, hash_type) in [
+            ("type", ScriptHashType::Type),
+            ("data", ScriptHashType::Data),
+            ("data1", ScriptHashType::Data1),
+            ("data2", ScriptHashType::Data2),
+        ] {
+            let deployment = parse(&format!("v2={}:{}", CODE_HASH, name)).unwrap();
+            assert_eq!(deployment.hash_type, hash_type);
+        }
+    }
+
+    #[test]
+    fn deployment_block_ranges() {
+        let range = |s: &str| {
+            let deployment = parse(&format!("v1={}{}", CODE_HASH, s)).unwrap();
+            (deployment.start, deployment.end)
+        };
+        assert_eq!(range("@1300000"), (1300000, None));
+        assert_eq!(range("@..1300000"), (0, Some(1300000)));
+        assert_eq!(range("@100..200"), (100, Some(200)));
+        assert_eq!(range("@100.."), (100, None));
+        let deployment = parse(&format!("v2={}:data1@1300000", CODE_HASH)).unwrap();
+        assert_eq!(deployment.hash_type, ScriptHashType::Data1);
+        assert_eq!((deployment.start, deployment.end), (1300000, None));
+    }
+
+    #[test]
+    fn deployment_activity() {
+        let deployment = parse(&format!("v1={}@100..200", CODE_HASH)).unwrap();
+        assert!(!deployment.is_active(99));
+        assert!(deployment.is_active(100));
+        assert!(deployment.is_active(199));
+        assert!(!deployment.is_active(200));
+    }
+
+    #[test]
+    fn malformed_deployments() {
+        for s in [
+            CODE_HASH.to_string(),
+            format!("={}", CODE_HASH),
+            "v1=0x1234".to_string(),
+            "v1=".to_string(),
+            format!("v1={}:data3", CODE_HASH),
+            format!("v1={}@abc", CODE_HASH),
+            format!("v1={}@..-1", CODE_HASH),
+            format!("v1={}@1..2..3", CODE_HASH),
+        ] {
+            assert!(parse(&s).is_err(), "{} is accepted", s);
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{IndexerTip, Network, RpcClient, RpcError, config};
+
+use futures::future::join_all;
+use reqwest::Url;
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+/// First cool-down of an endpoint after a failed call, doubled on every further failure.
+const COOLDOWN: Duration = Duration::from_secs(5);
+const MAX_COOLDOWN: Duration = Duration::from_secs(5 * 60);
+
+/// The RPC endpoints of one network.
+///
+/// Calls go to the healthiest endpoint: one whose indexer is not lagging more than
+/// `max_lag` blocks behind the best endpoint and that is not cooling down after a failure,
+/// preferring the highest indexer tip.
+pub struct Endpoints {
+    endpoints: Vec<Endpoint>,
+    max_lag: u64,
+}
+
+struct Endpoint {
+    url: Url,
+    health: Mutex<Health>,
+}
+
+#[derive(Default)]
+struct Health {
+    /// Indexer tip seen by the last [`Endpoints::refresh`], `None` if it failed.
+    tip: Option<IndexerTip>,
+    consecutive_failures: u32,
+    down_until: Option<Instant>,
+}
+
+impl Health {
+    fn failed(&mut self) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        let cooldown = COOLDOWN
+            .saturating_mul(1 << self.consecutive_failures.min(16).saturating_sub(1))
+            .min(MAX_COOLDOWN);
+        self.down_until = Some(Instant::now() + cooldown);
+    }
+
+    fn succeeded(&mut self) {
+        self.consecutive_failures = 0;
+        self.down_until = None;
+    }
+}
+
+impl Endpoints {
+    pub fn new(urls: Vec<Url>, max_lag: u64) -> Self {
+        assert!(!urls.is_empty(), "at least one RPC endpoint is required");
+        Self {
+            endpoints: urls
+                .into_iter()
+                .map(|url| Endpoint {
+                    url,
+                    health: Mutex::new(Health::default()),
+                })
+                .collect(),
+            max_lag,
+        }
+    }
+
+    pub fn urls(&self) -> impl Iterator<Item = &Url> {
+        self.endpoints.iter().map(|endpoint| &endpoint.url)
+    }
+
+    /// Ask every endpoint for its indexer tip and return the lowest tip among the endpoints
+    /// in rotation, so that whichever of them serves a call has indexed up to it.
+    pub async fn refresh(&self, rpc: &RpcClient) -> Result<IndexerTip, RpcError> {
+        let results = join_all(
+            self.endpoints
+                .iter()
+                .map(|endpoint| rpc.get_indexer_tip(endpoint.url.clone())),
+        )
+        .await;
+
+        let mut last_error = None;
+        for (endpoint, result) in self.endpoints.iter().zip(results) {
+            let mut health = endpoint.health.lock().unwrap();
+            match result {
+                Ok(tip) => {
+                    health.tip = Some(tip);
+                    health.succeeded();
+                }
+                Err(e) => {
+                    log::warn!("RPC endpoint {} is unavailable: {}", endpoint.url, e);
+                    health.tip = None;
+                    health.failed();
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        let best = self.best_tip();
+        self.endpoints
+            .iter()
+            .filter_map(|endpoint| {
+                let health = endpoint.health.lock().unwrap();
+                let tip = health.tip.clone()?;
+                if best.saturating_sub(tip.block_number.value()) > self.max_lag {
+                    log::warn!(
+                        "RPC endpoint {} is lagging at block number {}, best is {}",
+                        endpoint.url,
+                        tip.block_number.value(),
+                        best
+                    );
+                    return None;
+                }
+                Some(tip)
+            })
+            .min_by_key(|tip| tip.block_number.value())
+            .ok_or_else(|| last_error.expect("no tip without an error"))
+    }
+
+    /// Run `f` against the healthiest endpoint and record whether it succeeded, a failed
+    /// endpoint is skipped by the following calls until its cool-down is over.
+    pub async fn call<T, F, Fut>(&self, f: F) -> Result<T, RpcError>
+    where
+        F: FnOnce(Url) -> Fut,
+        Fut: Future<Output = Result<T, RpcError>>,
+    {
+        let endpoint = self.pick();
+        let res = f(endpoint.url.clone()).await;
+        let mut health = endpoint.health.lock().unwrap();
+        match &res {
+            Ok(_) => health.succeeded(),
+            Err(_) => health.failed(),
+        }
+        res
+    }
+
+    fn best_tip(&self) -> u64 {
+        self.endpoints
+            .iter()
+            .filter_map(|endpoint| {
+                let health = endpoint.health.lock().unwrap();
+                health.tip.as_ref().map(|tip| tip.block_number.value())
+            })
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn pick(&self) -> &Endpoint {
+        let best = self.best_tip();
+        let now = Instant::now();
+        self.endpoints
+            .iter()
+            .min_by_key(|endpoint| {
+                let health = endpoint.health.lock().unwrap();
+                let lag = health
+                    .tip
+                    .as_ref()
+                    .map(|tip| best.saturating_sub(tip.block_number.value()));
+                // rank: in rotation first, then not cooling down, then the smallest lag
+                let lagging = lag.is_none_or(|lag| lag > self.max_lag);
+                let down_until = health.down_until.filter(|until| *until > now);
+                (
+                    lagging,
+                    down_until.is_some(),
+                    down_until,
+                    lag.unwrap_or(u64::MAX),
+                    health.consecutive_failures,
+                )
+            })
+            .expect("at least one RPC endpoint is required")
+    }
+}
+
+/// The endpoints configured for `net`.
+pub fn endpoints(net: Network) -> &'static Endpoints {
+    static MAINNET: LazyLock<Endpoints> =
+        LazyLock::new(|| Endpoints::new(config().mainnet.rpc_urls.clone(), config().rpc_max_lag));
+    static TESTNET: LazyLock<Endpoints> =
+        LazyLock::new(|| Endpoints::new(config().testnet.rpc_urls.clone(), config().rpc_max_lag));
+    static CUSTOM: LazyLock<HashMap<&'static str, Endpoints>> = LazyLock::new(|| {
+        config()
+            .custom
+            .iter()
+            .map(|network| {
+                (
+                    network.name.as_str(),
+                    Endpoints::new(network.rpc_urls.clone(), config().rpc_max_lag),
+                )
+            })
+            .collect()
+    });
+    match net {
+        Network::Mainnet => &MAINNET,
+        Network::Testnet => &TESTNET,
+        Network::Custom(name) => &CUSTOM[name],
+    }
+}
@@ -0,0 +1,175 @@
+use crate::rpc_client::{Retryable, RpcError};
+use ckb_types::H256;
+
+use std::fmt;
+
+/// Why a step of the indexing pipeline failed.
+#[derive(Debug)]
+pub enum IndexerError {
+    /// The node could not be asked, see [`RpcError`].
+    Rpc(RpcError),
+    /// Reading or writing the database failed.
+    Db(sqlx::Error),
+    /// A transaction reported by the node cannot be indexed as a whole.
+    InvalidTransaction { tx_hash: H256, reason: RejectReason },
+    /// One cell of a transaction cannot be indexed, the rest of the transaction can.
+    InvalidCell {
+        tx_hash: H256,
+        index: u32,
+        reason: RejectReason,
+    },
+    /// No block of `[from, to)` has been indexed yet, `indexed` is the indexer state of the
+    /// network if it has one.
+    NothingToReindex {
+        from: u64,
+        to: u64,
+        indexed: Option<u64>,
+    },
+}
+
+/// Why a transaction or cell was not indexed, stored as [`RejectReason::code`] in the
+/// `did_rejected` tables.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RejectReason {
+    BlockTimestampOutOfRange,
+    NoSuchInput,
+    NoSuchOutput,
+    NoOutputData,
+    MalformedDidoc,
+    DidocCheckFailed,
+    NoTypeScript,
+    ShortTypeArgs,
+}
+
+impl RejectReason {
+    pub fn code(&self) -> &'static str {
+        match self {
+            RejectReason::BlockTimestampOutOfRange => "block_timestamp_out_of_range",
+            RejectReason::NoSuchInput => "no_such_input",
+            RejectReason::NoSuchOutput => "no_such_output",
+            RejectReason::NoOutputData => "no_output_data",
+            RejectReason::MalformedDidoc => "malformed_didoc",
+            RejectReason::DidocCheckFailed => "didoc_check_failed",
+            RejectReason::NoTypeScript => "no_type_script",
+            RejectReason::ShortTypeArgs => "short_type_args",
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RejectReason::BlockTimestampOutOfRange => "block timestamp is out of range",
+            RejectReason::NoSuchInput => "no such input",
+            RejectReason::NoSuchOutput => "no such output",
+            RejectReason::NoOutputData => "no data for output",
+            RejectReason::MalformedDidoc => "malformed DIDoc cell data",
+            RejectReason::DidocCheckFailed => "DIDoc check failed",
+            RejectReason::NoTypeScript => "no type script",
+            RejectReason::ShortTypeArgs => "type script args shorter than 20 bytes",
+        })
+    }
+}
+
+impl IndexerError {
+    pub(crate) fn invalid_transaction(tx_hash: &H256, reason: RejectReason) -> Self {
+        IndexerError::InvalidTransaction {
+            tx_hash: tx_hash.clone(),
+            reason,
+        }
+    }
+
+    pub(crate) fn invalid_cell(tx_hash: &H256, index: u32, reason: RejectReason) -> Self {
+        IndexerError::InvalidCell {
+            tx_hash: tx_hash.clone(),
+            index,
+            reason,
+        }
+    }
+}
+
+impl Retryable for IndexerError {
+    /// Lost connections, pool timeouts and serialization failures are transient, the data
+    /// of an invalid transaction stays invalid.
+    fn is_retryable(&self) -> bool {
+        match self {
+            IndexerError::Rpc(e) => e.is_retryable(),
+            IndexerError::Db(e) => match e {
+                sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::WorkerCrashed => true,
+                // serialization_failure, deadlock_detected, too_many_connections,
+                // admin_shutdown and every connection_exception
+                sqlx::Error::Database(e) => e.code().is_some_and(|code| {
+                    matches!(code.as_ref(), "40001" | "40P01" | "53300" | "57P01")
+                        || code.starts_with("08")
+                }),
+                _ => false,
+            },
+            IndexerError::InvalidTransaction { .. }
+            | IndexerError::InvalidCell { .. }
+            | IndexerError::NothingToReindex { .. } => false,
+        }
+    }
+}
+
+impl fmt::Display for IndexerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexerError::Rpc(e) => write!(f, "rpc error: {}", e),
+            IndexerError::Db(e) => write!(f, "database error: {}", e),
+            IndexerError::InvalidTransaction { tx_hash, reason } => {
+                write!(f, "invalid tx: {}: {}", tx_hash, reason)
+            }
+            IndexerError::InvalidCell {
+                tx_hash,
+                index,
+                reason,
+            } => write!(
+                f,
+                "invalid cell at tx: {}, index: {}: {}",
+                tx_hash, index, reason
+            ),
+            IndexerError::NothingToReindex {
+                from,
+                to,
+                indexed: Some(indexed),
+            } => write!(
+                f,
+                "nothing to re-index from block number {} to {}, blocks are indexed below {}",
+                from, to, indexed
+            ),
+            IndexerError::NothingToReindex {
+                from,
+                to,
+                indexed: None,
+            } => write!(
+                f,
+                "nothing to re-index from block number {} to {}, the network was never indexed",
+                from, to
+            ),
+        }
+    }
+}
+
+impl std::error::Error for IndexerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            IndexerError::Rpc(e) => Some(e),
+            IndexerError::Db(e) => Some(e),
+            IndexerError::InvalidTransaction { .. }
+            | IndexerError::InvalidCell { .. }
+            | IndexerError::NothingToReindex { .. } => None,
+        }
+    }
+}
+
+impl From<RpcError> for IndexerError {
+    fn from(e: RpcError) -> Self {
+        IndexerError::Rpc(e)
+    }
+}
+
+impl From<sqlx::Error> for IndexerError {
+    fn from(e: sqlx::Error) -> Self {
+        IndexerError::Db(e)
+    }
+}
@@ -1,4 +1,7 @@
-use crate::{Network, get_pg_pool, pg_read::DidRead};
+use crate::{
+    Network, get_pg_pool,
+    pg_read::{DidRead, DidRejectedRead, indexer_status},
+};
 
 use salvo::{Request, Response, handler, macros::Extractible};
 use serde::{Deserialize, Serialize};
@@ -17,13 +20,35 @@ pub(crate) struct Params {
     #[serde(default)]
     pub(crate) page: usize,
     pub(crate) page_size: Option<usize>,
+    /// List every indexed cell, consumed ones included, instead of the current documents.
+    #[serde(default)]
+    pub(crate) history: bool,
+}
+
+#[derive(Serialize, Deserialize, Extractible)]
+#[salvo(extract(default_source(from = "query")))]
+pub(crate) struct RejectedParams {
+    #[serde(default)]
+    pub(crate) net: Network,
+    pub(crate) did: Option<String>,
+    pub(crate) tx_hash: Option<String>,
+    #[serde(default)]
+    pub(crate) page: usize,
+    pub(crate) page_size: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Extractible)]
+#[salvo(extract(default_source(from = "query")))]
+pub(crate) struct NetParams {
+    #[serde(default)]
+    pub(crate) net: Network,
 }
 
 #[handler]
 pub async fn did_from_id(req: &mut Request, _res: &mut Response) -> Result<String, salvo::Error> {
     let params: Params = req.extract().await?;
     let pool = get_pg_pool();
-    let res = DidRead::fetch_by_did(&pool, params)
+    let res = DidRead::fetch_by_did(pool, params)
         .await
         .map(|(records, next_page)| {
             serde_json::json!({
@@ -43,7 +68,7 @@ pub async fn did_from_id(req: &mut Request, _res: &mut Response) -> Result<Strin
 pub async fn did_from_addr(req: &mut Request, _res: &mut Response) -> Result<String, salvo::Error> {
     let params: Params = req.extract().await?;
     let pool = get_pg_pool();
-    let res = DidRead::fetch_by_address(&pool, params)
+    let res = DidRead::fetch_by_address(pool, params)
         .await
         .map(|(records, next_page)| {
             serde_json::json!({
@@ -66,7 +91,7 @@ pub async fn did_from_signing_key(
 ) -> Result<String, salvo::Error> {
     let params: Params = req.extract().await?;
     let pool = get_pg_pool();
-    let res = DidRead::fetch_by_signing_key(&pool, params)
+    let res = DidRead::fetch_by_signing_key(pool, params)
         .await
         .map(|(records, next_page)| {
             serde_json::json!({
@@ -91,7 +116,7 @@ pub async fn did_from_handle(
 ) -> Result<String, salvo::Error> {
     let params: Params = req.extract().await?;
     let pool = get_pg_pool();
-    let res = DidRead::fetch_by_handle(&pool, params)
+    let res = DidRead::fetch_by_handle(pool, params)
         .await
         .map(|(records, next_page)| {
             serde_json::json!({
@@ -114,7 +139,7 @@ pub async fn did_from_lock_script_hash(
 ) -> Result<String, salvo::Error> {
     let params: Params = req.extract().await?;
     let pool = get_pg_pool();
-    let res = DidRead::fetch_by_lock_script_hash(&pool, params)
+    let res = DidRead::fetch_by_lock_script_hash(pool, params)
         .await
         .map(|(records, next_page)| {
             serde_json::json!({
@@ -131,3 +156,55 @@ pub async fn did_from_lock_script_hash(
 
     Ok(res.to_string())
 }
+
+#[handler]
+pub async fn did_history(req: &mut Request, _res: &mut Response) -> Result<String, salvo::Error> {
+    let params: Params = req.extract().await?;
+    let pool = get_pg_pool();
+    let res = DidRead::fetch_history(pool, params)
+        .await
+        .map(|(records, next_page)| {
+            serde_json::json!({
+                "records": records,
+                "next_page": next_page
+            })
+        })
+        .map_err(|e| {
+            log::warn!("fetch history error: {}", e);
+            salvo::Error::Io(std::io::Error::other("Failed to fetch did history"))
+        })?;
+
+    Ok(res.to_string())
+}
+
+#[handler]
+pub async fn did_rejected(req: &mut Request, _res: &mut Response) -> Result<String, salvo::Error> {
+    let params: RejectedParams = req.extract().await?;
+    let pool = get_pg_pool();
+    let res = DidRejectedRead::fetch(pool, params)
+        .await
+        .map(|(records, next_page)| {
+            serde_json::json!({
+                "records": records,
+                "next_page": next_page
+            })
+        })
+        .map_err(|e| {
+            log::warn!("fetch rejected error: {}", e);
+            salvo::Error::Io(std::io::Error::other("Failed to fetch rejected did cells"))
+        })?;
+
+    Ok(res.to_string())
+}
+
+#[handler]
+pub async fn indexer_state(req: &mut Request, _res: &mut Response) -> Result<String, salvo::Error> {
+    let params: NetParams = req.extract().await?;
+    let pool = get_pg_pool();
+    let res = indexer_status(pool, params.net).await.map_err(|e| {
+        log::warn!("fetch indexer state error: {}", e);
+        salvo::Error::Io(std::io::Error::other("Failed to fetch indexer state"))
+    })?;
+
+    Ok(res.to_string())
+}
@@ -0,0 +1,124 @@
+use crate::{Network, config};
+use sqlx::{Pool, Postgres};
+
+use std::collections::HashSet;
+
+/// A schema change, applied once to the shared tables and once to the tables of every
+/// network, each recorded in `schema_migrations`.
+struct Migration {
+    version: i32,
+    description: &'static str,
+    /// Statements on the tables shared by every network, such as `indexer_state`.
+    shared: &'static str,
+    /// Statements on the tables of one network, `{suffix}` is replaced with
+    /// [`Network::table_suffix`].
+    network: &'static str,
+}
+
+/// In order of `version`, which is never reused. Released migrations are never edited,
+/// changes go into a new one.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "baseline",
+        shared: include_str!("../db_schema/migrations/0001_baseline.shared.sql"),
+        network: include_str!("../db_schema/migrations/0001_baseline.network.sql"),
+    },
+    Migration {
+        version: 2,
+        description: "native types",
+        shared: include_str!("../db_schema/migrations/0002_native_types.shared.sql"),
+        network: include_str!("../db_schema/migrations/0002_native_types.network.sql"),
+    },
+    Migration {
+        version: 3,
+        description: "current documents",
+        shared: include_str!("../db_schema/migrations/0003_did_current.shared.sql"),
+        network: include_str!("../db_schema/migrations/0003_did_current.network.sql"),
+    },
+    Migration {
+        version: 4,
+        description: "version lineage",
+        shared: include_str!("../db_schema/migrations/0004_did_lineage.shared.sql"),
+        network: include_str!("../db_schema/migrations/0004_did_lineage.network.sql"),
+    },
+];
+
+/// `network` of the history rows of shared migrations.
+const SHARED: &str = "";
+
+/// Held while migrating so that processes starting together apply every migration once.
+const MIGRATION_LOCK: i64 = 0x7765_6235_6d69_6772;
+
+const HISTORY_SQL: &str = "create table if not exists schema_migrations (
+    network text not null,
+    version integer not null,
+    description text not null,
+    applied_at TIMESTAMPTZ not null default now(),
+    primary key (network, version)
+)";
+
+/// A migration applied by [`migrate`].
+#[derive(Clone, Debug)]
+pub struct AppliedMigration {
+    /// `None` for the shared tables.
+    pub network: Option<Network>,
+    pub version: i32,
+    pub description: &'static str,
+}
+
+/// Bring the tables of every configured network up to the latest migration.
+///
+/// Everything is applied in one transaction, so a failing migration leaves the schema as it
+/// was. A network configured later starts from the first migration on its own.
+pub async fn migrate(pool: &Pool<Postgres>) -> Result<Vec<AppliedMigration>, sqlx::Error> {
+    let mut conn = pool.begin().await?;
+    sqlx::query("SELECT pg_advisory_xact_lock($1)")
+        .bind(MIGRATION_LOCK)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::raw_sql(HISTORY_SQL).execute(&mut *conn).await?;
+    let history: HashSet<(String, i32)> =
+        sqlx::query_as("SELECT network, version FROM schema_migrations")
+            .fetch_all(&mut *conn)
+            .await?
+            .into_iter()
+            .collect();
+
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS {
+        let scopes = std::iter::once(None).chain(config().networks().map(Some));
+        for network in scopes {
+            let name = network.map_or(SHARED, |net| net.name());
+            if history.contains(&(name.to_string(), migration.version)) {
+                continue;
+            }
+            let sql = match network {
+                None => migration.shared.to_string(),
+                Some(net) => migration.network.replace("{suffix}", &net.table_suffix()),
+            };
+            sqlx::raw_sql(&sql).execute(&mut *conn).await?;
+            sqlx::query(
+                "INSERT INTO schema_migrations (network, version, description) VALUES ($1, $2, $3)",
+            )
+            .bind(name)
+            .bind(migration.version)
+            .bind(migration.description)
+            .execute(&mut *conn)
+            .await?;
+            log::info!(
+                "Applied migration {} {} to {}",
+                migration.version,
+                migration.description,
+                network.map_or("the shared tables", |net| net.name())
+            );
+            applied.push(AppliedMigration {
+                network,
+                version: migration.version,
+                description: migration.description,
+            });
+        }
+    }
+    conn.commit().await?;
+    Ok(applied)
+}
@@ -1,240 +1,1121 @@
 use crate::{
-    CKB_MAINNET_RPC, CKB_TESTNET_RPC, CellType, IndexerScriptSearchMode, Network, Order, RpcClient,
-    ScriptType, SearchKey, SearchKeyFilter, Tx, calculate_address, calculate_web5_did,
-    check_did_doc, did_script, parse_didoc_cell,
+    CellType, Endpoints, IndexerScriptSearchMode, IndexerTip, Network, Order, RpcClient,
+    ScriptType, SearchKey, SearchKeyFilter, Tx, TxWithCells, Web5DocumentData, calculate_address,
+    calculate_web5_did, check_did_doc,
+    config::DidDeployment,
+    error::{IndexerError, RejectReason},
+    parse_didoc_cell,
+    pg_write::{
+        BlockCheckpoint, DidDelete, DidPending, DidRejected, DidWrite, IndexerState,
+        commit_block_range, global_cache_of, reindex_block_range, set_indexer_state,
+    },
+    rpc_client::RpcError,
 };
 
 use chrono::DateTime;
+use ckb_jsonrpc_types::{BlockNumber, HeaderView, Status, TransactionView};
 use ckb_sdk::util::blake160;
-use ckb_types::{packed, prelude::Entity};
+use ckb_types::{H160, H256, packed, prelude::Entity};
 
-use std::sync::Arc;
+use futures::{StreamExt, TryStreamExt, stream};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, hash_map::Entry},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{Notify, mpsc},
+    task::JoinSet,
+};
+
+/// How many of the latest checkpoints are compared against the chain when looking for a fork.
+const CHECKPOINT_LOOKBACK: i64 = 100;
+
+/// How long a pending transaction may stay unknown to the RPC endpoints before it is dropped,
+/// they do not necessarily share the pool of the subscription endpoint.
+const PENDING_UNKNOWN_GRACE: chrono::TimeDelta = chrono::TimeDelta::minutes(10);
+
+/// What the stored checkpoints say about the chain seen by the endpoints.
+#[derive(Debug, PartialEq, Eq)]
+enum ForkCheck {
+    /// The newest checkpoint at or below the tip is canonical, or there is none yet.
+    Canonical,
+    /// Blocks above this checkpoint were reorganized away and must be rolled back.
+    Fork(BlockNumber),
+    /// Every checkpoint is above the tip, for example while the node resyncs or after a
+    /// failover to a lagging endpoint, nothing can be decided until it catches up.
+    Undecided,
+    /// No checkpoint at or below the tip is canonical, the fork is deeper than the lookback.
+    TooDeep,
+}
 
-pub async fn did_monitor(rpc: &RpcClient) {
-    let (tip_testnet, tip) = loop {
-        let testnet_tip = rpc.get_indexer_tip(CKB_TESTNET_RPC.clone()).await;
-        let mainnet_tip = rpc.get_indexer_tip(CKB_MAINNET_RPC.clone()).await;
-        if let (Ok(tip_testnet), Ok(tip)) = (testnet_tip, mainnet_tip) {
-            break (tip_testnet, tip);
+/// Compare stored checkpoints with the canonical chain, newest first.
+///
+/// Checkpoints above `tip` cannot be checked and are left out, `block_hash` looks up the
+/// canonical hash of the others. A fork is only reported below a checkpoint whose hash
+/// differs from the chain, at the highest checkpoint that is still on it.
+async fn find_fork_point<F, Fut>(
+    tip: BlockNumber,
+    checkpoints: &[(BlockNumber, H256)],
+    mut block_hash: F,
+) -> Result<ForkCheck, RpcError>
+where
+    F: FnMut(BlockNumber) -> Fut,
+    Fut: Future<Output = Result<H256, RpcError>>,
+{
+    if checkpoints.is_empty() {
+        return Ok(ForkCheck::Canonical);
+    }
+    let checkable = checkpoints
+        .iter()
+        .filter(|(number, _)| number.value() <= tip.value());
+    let mut checked = false;
+    for (number, hash) in checkable {
+        if &block_hash(*number).await? == hash {
+            return Ok(if checked {
+                ForkCheck::Fork(*number)
+            } else {
+                ForkCheck::Canonical
+            });
+        }
+        checked = true;
+    }
+    Ok(if checked {
+        ForkCheck::TooDeep
+    } else {
+        ForkCheck::Undecided
+    })
+}
+
+/// Index `net` forever: right after every new tip announced by its subscription
+/// endpoint, and at least every `poll_interval_secs`.
+///
+/// With a subscription endpoint, DID transactions entering its pool are tracked as pending
+/// until they are indexed from a block or leave the pool. The subscriptions stop when the
+/// returned future is dropped.
+pub async fn run_monitor(rpc: RpcClient, net: Network) {
+    let config = crate::config().network(net);
+    let new_tip = Arc::new(Notify::new());
+    // aborted when this future is dropped, such as when it panics and is restarted
+    let mut subscriptions = JoinSet::new();
+    if let Some(addr) = &config.subscribe_addr {
+        subscriptions.spawn(crate::subscription::subscribe_new_tip(
+            addr.clone(),
+            new_tip.clone(),
+        ));
+        let (txs, pool_txs) = mpsc::unbounded_channel();
+        subscriptions.spawn(crate::subscription::subscribe_new_transaction(
+            addr.clone(),
+            txs,
+        ));
+        subscriptions.spawn(track_pending(net, pool_txs));
+    }
+
+    loop {
+        did_monitor(&rpc, net).await;
+        settle_pending(&rpc, net).await;
+        tokio::select! {
+            _ = new_tip.notified() => (),
+            _ = tokio::time::sleep(Duration::from_secs(config.poll_interval_secs)) => (),
+        }
+    }
+}
+
+/// Index `net` from its stored checkpoint up to the confirmed part of the current indexer tip.
+pub async fn did_monitor(rpc: &RpcClient, net: Network) {
+    let endpoints = crate::endpoints(net);
+    let retry = &crate::config().rpc_retry;
+    let net_tip = &match retry
+        .retry(format!("{:?} get_indexer_tip", net), || {
+            endpoints.refresh(rpc)
+        })
+        .await
+    {
+        Ok(tip) => tip,
+        Err(e) => {
+            log::error!(
+                "{:?} Giving up on this indexing run, no indexer tip: {}",
+                net,
+                e
+            );
+            return;
         }
-        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
     };
 
-    for net in [Network::Mainnet, Network::Testnet] {
-        let last_number = match net {
-            Network::Mainnet => crate::pg_write::global_cache().load(),
-            Network::Testnet => crate::pg_write::global_cache_testnet().load(),
+    let global_cache = global_cache_of(net);
+    // the state row may have been moved by an operator since the last cycle
+    match IndexerState::fetch(crate::get_pg_pool(), net).await {
+        Ok(Some(state)) => global_cache.store(Arc::new(state.block_number)),
+        Ok(None) => (),
+        Err(e) => {
+            log::error!("{:?} Failed to load indexer state: {}", net, e);
+            return;
+        }
+    }
+
+    let checkpoints =
+        match BlockCheckpoint::fetch_latest(crate::get_pg_pool(), net, CHECKPOINT_LOOKBACK).await {
+            Ok(checkpoints) => checkpoints,
+            Err(e) => {
+                log::error!("{:?} Failed to load block checkpoints: {}", net, e);
+                return;
+            }
         };
-        log::info!(
-            "Starting DID monitor for {:?}, from {}, to {}",
+    let fork_point = match find_fork_point(
+        net_tip.block_number,
+        &checkpoints,
+        |number| async move {
+            fetch_header(rpc, endpoints, number)
+                .await
+                .map(|header| header.hash)
+        },
+    )
+    .await
+    {
+        Ok(fork_point) => fork_point,
+        Err(e) => {
+            log::error!(
+                "{:?} Giving up on this indexing run, block checkpoints could not be checked: {}",
+                net,
+                e
+            );
+            return;
+        }
+    };
+    let fork_number = match fork_point {
+        ForkCheck::Canonical => None,
+        ForkCheck::Fork(fork_number) => Some(fork_number),
+        ForkCheck::Undecided => {
+            log::warn!(
+                "{:?} Indexer tip {} is below every block checkpoint, waiting for the endpoints to catch up",
+                net,
+                net_tip.block_number.value()
+            );
+            return;
+        }
+        ForkCheck::TooDeep => {
+            // rewinding further would drop everything indexed so far, leave it to an operator
+            log::error!(
+                "{:?} None of the latest {} block checkpoints is on the chain any more, \
+                rewind with `web5-indexer set-state` once the node is trusted again",
+                net,
+                CHECKPOINT_LOOKBACK
+            );
+            return;
+        }
+    };
+    if let Some(fork_number) = fork_number {
+        log::warn!(
+            "{:?} Chain reorganization detected, rolling back to block number {}",
             net,
-            last_number.value(),
-            match net {
-                Network::Mainnet => tip.block_number.value(),
-                Network::Testnet => tip_testnet.block_number.value(),
+            fork_number.value()
+        );
+        let rolled_back = retry
+            .retry(format!("{:?} rollback", net), || async {
+                set_indexer_state(crate::get_pg_pool(), net, fork_number)
+                    .await
+                    .map_err(IndexerError::from)
+            })
+            .await;
+        if let Err(e) = rolled_back {
+            log::error!("{:?} Failed to roll back reorganized blocks: {}", net, e);
+            return;
+        }
+    }
+
+    // the newest blocks may still be reorganized away, hold them back until confirmed
+    let confirmations = crate::config().network(net).confirmations;
+    let target = &if confirmations == 0 {
+        net_tip.clone()
+    } else {
+        let number = net_tip.block_number.value().saturating_sub(confirmations);
+        match fetch_header(rpc, endpoints, number.into()).await {
+            Ok(header) => IndexerTip {
+                block_number: number.into(),
+                block_hash: header.hash,
+            },
+            Err(e) => {
+                log::error!(
+                    "{:?} Giving up on this indexing run, no header at block number {}: {}",
+                    net,
+                    number,
+                    e
+                );
+                return;
             }
+        }
+    };
+    if let Err(e) = IndexerState::store_tip(crate::get_pg_pool(), net, net_tip.block_number).await {
+        log::warn!("{:?} Failed to record chain tip: {}", net, e);
+    }
+
+    let window_size = crate::config().window_size.max(1);
+    let mut start = **global_cache.load();
+    log::info!(
+        "Starting DID monitor for {:?}, from {}, to {}",
+        net,
+        start.value(),
+        target.block_number.value()
+    );
+
+    while start.value() < target.block_number.value() {
+        let end = std::cmp::min(
+            start.value().saturating_add(window_size),
+            target.block_number.value(),
         );
-        let url = match net {
-            Network::Mainnet => CKB_MAINNET_RPC.clone(),
-            Network::Testnet => CKB_TESTNET_RPC.clone(),
+        let fetched = async {
+            let window_tip = if end == target.block_number.value() {
+                target.clone()
+            } else {
+                let header = fetch_header(rpc, endpoints, end.into()).await?;
+                IndexerTip {
+                    block_number: end.into(),
+                    block_hash: header.hash,
+                }
+            };
+            let range =
+                fetch_block_range(rpc, endpoints, net, start, window_tip.block_number).await?;
+            Ok::<_, IndexerError>((window_tip, range))
+        }
+        .await;
+        let (
+            window_tip,
+            BlockRange {
+                dids,
+                did_deletes,
+                rejected,
+                mut block_hashes,
+                skipped,
+            },
+        ) = match fetched {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                // the retry policy is exhausted, the next run resumes from `start`
+                log::error!(
+                    "{:?} Giving up on this indexing run at block number {}: {}",
+                    net,
+                    start.value(),
+                    e
+                );
+                break;
+            }
         };
 
-        let search_key = SearchKey {
-            script: did_script(net, ckb_jsonrpc_types::JsonBytes::default()),
-            script_type: ScriptType::Type,
-            filter: Some(SearchKeyFilter::block_range(
-                *last_number.as_ref(),
-                match net {
-                    Network::Mainnet => tip.block_number,
-                    Network::Testnet => tip_testnet.block_number,
-                },
-            )),
-            with_data: None,
-            script_search_mode: Some(IndexerScriptSearchMode::Prefix),
-            group_by_transaction: Some(true),
-        };
+        block_hashes.insert(
+            window_tip.block_number.value(),
+            window_tip.block_hash.clone(),
+        );
+        let checkpoints = block_hashes
+            .iter()
+            .map(|(number, hash)| BlockCheckpoint::new((*number).into(), hash))
+            .collect::<Vec<_>>();
+        for e in &skipped {
+            log::warn!("{:?} Skipping {}", net, e);
+        }
+        log::info!(
+            "{:?} Inserting {} DID entries, deleting {} DID entries, skipping {} up to block number {}",
+            net,
+            dids.len(),
+            did_deletes.len(),
+            skipped.len(),
+            window_tip.block_number.value()
+        );
+        let committed = retry
+            .retry(format!("{:?} commit", net), || async {
+                commit_block_range(
+                    crate::get_pg_pool(),
+                    net,
+                    start,
+                    &dids,
+                    &did_deletes,
+                    &rejected,
+                    &checkpoints,
+                    &window_tip,
+                )
+                .await
+                .map_err(IndexerError::from)
+            })
+            .await;
+        match committed {
+            Ok(None) => {
+                // moved by an operator while the window was fetched, the next run resumes
+                // from wherever it is now
+                log::warn!(
+                    "{:?} Indexer state moved away from block number {}, dropping the window up to {}",
+                    net,
+                    start.value(),
+                    window_tip.block_number.value()
+                );
+                break;
+            }
+            Ok(Some(0)) => {}
+            Ok(Some(orphans)) => log::warn!(
+                "{:?} {} consumed DID cells from block number {} to {} were never indexed",
+                net,
+                orphans,
+                start.value(),
+                window_tip.block_number.value()
+            ),
+            Err(e) => {
+                // nothing of this window was written, it is indexed again on the next cycle
+                log::error!(
+                    "{:?} Failed to commit DID entries from block number {} to {}: {}",
+                    net,
+                    start.value(),
+                    window_tip.block_number.value(),
+                    e
+                );
+                break;
+            }
+        }
+        start = window_tip.block_number;
+    }
+
+    log::info!(
+        "Finished processing DID cells for {:?} up to block number {:?}",
+        net,
+        start.value()
+    );
+}
+
+/// Index `[from, to)` of `net` again, for example after a parser fix, without wiping the
+/// rest of its table.
+///
+/// Only blocks below the indexer state are re-indexed, the monitor covers the ones above,
+/// a range left empty by that is an error.
+/// Rows are upserted and only the rows of the range are removed, so this can run while the
+/// monitor is indexing the same network.
+pub async fn reindex(
+    rpc: &RpcClient,
+    net: Network,
+    from: BlockNumber,
+    to: BlockNumber,
+) -> Result<(), IndexerError> {
+    let pool = crate::get_pg_pool();
+    let endpoints = crate::endpoints(net);
+    let retry = &crate::config().rpc_retry;
+    retry
+        .retry(format!("{:?} get_indexer_tip", net), || {
+            endpoints.refresh(rpc)
+        })
+        .await?;
+    let indexed = IndexerState::fetch(pool, net)
+        .await?
+        .map(|state| state.block_number.value());
+    let clamped = std::cmp::min(to.value(), indexed.unwrap_or_default());
+    if from.value() >= clamped {
+        return Err(IndexerError::NothingToReindex {
+            from: from.value(),
+            to: to.value(),
+            indexed,
+        });
+    }
+    let to = clamped;
+    let window_size = crate::config().window_size.max(1);
 
-        let mut raw_tx_with_cell = Vec::new();
-        let mut after_cursor = None;
-        loop {
-            match rpc
-                .get_transactions(
-                    url.clone(),
-                    search_key.clone(),
-                    Order::Asc,
-                    500.into(),
-                    after_cursor.clone(),
+    let mut start = from.value();
+    log::info!("Re-indexing {:?} from {} to {}", net, start, to);
+    while start < to {
+        let end = std::cmp::min(start.saturating_add(window_size), to);
+        let range = fetch_block_range(rpc, endpoints, net, start.into(), end.into()).await?;
+        for e in &range.skipped {
+            log::warn!("{:?} Skipping {}", net, e);
+        }
+        log::info!(
+            "{:?} Re-indexing {} DID entries, {} consumptions, {} rejections from block number {} to {}",
+            net,
+            range.dids.len(),
+            range.did_deletes.len(),
+            range.rejected.len(),
+            start,
+            end
+        );
+        let orphans = retry
+            .retry(format!("{:?} re-index commit", net), || async {
+                reindex_block_range(
+                    pool,
+                    net,
+                    start.into(),
+                    end.into(),
+                    &range.dids,
+                    &range.did_deletes,
+                    &range.rejected,
                 )
                 .await
-            {
-                Ok(result) => {
-                    let has_more = result.objects.len() == 500;
-                    raw_tx_with_cell.extend(result.objects);
-                    if !has_more {
-                        break;
+                .map_err(IndexerError::from)
+            })
+            .await?;
+        if orphans > 0 {
+            log::warn!(
+                "{:?} {} consumed DID cells from block number {} to {} were never indexed",
+                net,
+                orphans,
+                start,
+                end
+            );
+        }
+        start = end;
+    }
+    Ok(())
+}
+
+/// Store the DID cells created by every transaction received from `pool_txs` as pending.
+async fn track_pending(net: Network, mut pool_txs: mpsc::UnboundedReceiver<TransactionView>) {
+    let deployments = &crate::config().network(net).deployments;
+    while let Some(tx) = pool_txs.recv().await {
+        let did_outputs = tx
+            .inner
+            .outputs
+            .iter()
+            .enumerate()
+            .filter_map(|(index, output)| {
+                let script = output.type_.as_ref()?;
+                deployments
+                    .iter()
+                    .any(|deployment| deployment.matches(script))
+                    .then_some((index, script))
+            })
+            .collect::<Vec<_>>();
+        if did_outputs.is_empty() {
+            continue;
+        }
+        // the transaction is committed above the latest tip at the earliest, a cell that no
+        // deployment indexes there would never leave the pending table
+        let next_block = match IndexerState::fetch(crate::get_pg_pool(), net).await {
+            Ok(state) => state.map_or(0, |state| {
+                state
+                    .tip_block_number
+                    .unwrap_or(state.block_number)
+                    .value()
+                    .saturating_add(1)
+            }),
+            Err(e) => {
+                log::error!("{:?} Failed to load indexer state: {}", net, e);
+                continue;
+            }
+        };
+        let pending = did_outputs
+            .into_iter()
+            .filter_map(|(index, script)| {
+                let deployment = deployments.iter().find(|deployment| {
+                    deployment.matches(script) && deployment.is_active(next_block)
+                })?;
+                Some((index, deployment))
+            })
+            .filter_map(|(index, deployment)| {
+                match parse_did_output(net, &tx.hash, &tx, index as u32) {
+                    Ok(cell) => Some((cell, deployment.name.clone())),
+                    Err(e) => {
+                        log::warn!("{:?} Not tracking pending {}", net, e);
+                        None
                     }
-                    after_cursor = Some(result.last_cursor);
-                }
-                Err(e) => {
-                    log::error!("Error fetching transactions: {:?}", e);
-                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
                 }
+            })
+            .map(|(cell, deployment)| {
+                DidPending::new(
+                    cell.did,
+                    cell.handle,
+                    cell.signing_key,
+                    cell.ckb_address,
+                    tx.hash.clone(),
+                    cell.outpoint,
+                    sqlx::types::Json(cell.did_document),
+                    cell.cell_data,
+                    cell.lock_script_hash,
+                    deployment,
+                )
+            })
+            .collect::<Vec<_>>();
+        if pending.is_empty() {
+            continue;
+        }
+        log::info!(
+            "{:?} Tracking {} pending DID entries of tx: {}",
+            net,
+            pending.len(),
+            tx.hash
+        );
+        if let Err(e) = DidPending::insert_batch(crate::get_pg_pool(), &pending, net).await {
+            log::error!("{:?} Failed to store pending DID entries: {}", net, e);
+        }
+    }
+}
+
+/// Drop the pending DID entries that have been indexed from a block since, whose
+/// transaction was rejected, or whose block was indexed without them, and record the pool
+/// status of the others.
+async fn settle_pending(rpc: &RpcClient, net: Network) {
+    let pool = crate::get_pg_pool();
+    let indexed = match IndexerState::fetch(pool, net).await {
+        Ok(state) => state.map_or(0, |state| state.block_number.value()),
+        Err(e) => {
+            log::error!("{:?} Failed to load indexer state: {}", net, e);
+            return;
+        }
+    };
+    match DidPending::promote(pool, net).await {
+        Ok(0) => (),
+        Ok(promoted) => log::info!("{:?} {} pending DID entries were indexed", net, promoted),
+        Err(e) => {
+            log::error!("{:?} Failed to promote pending DID entries: {}", net, e);
+            return;
+        }
+    }
+    let pending = match DidPending::fetch_txs(pool, net).await {
+        Ok(pending) => pending,
+        Err(e) => {
+            log::error!("{:?} Failed to load pending DID transactions: {}", net, e);
+            return;
+        }
+    };
+
+    let endpoints = crate::endpoints(net);
+    for (tx_hash, seen_at) in pending {
+        // a failed check is repeated after the next indexing run
+        let tx_status = match endpoints
+            .call(|url| rpc.get_transaction_status(url, &tx_hash))
+            .await
+        {
+            Ok(tx_status) => tx_status,
+            Err(e) => {
+                log::warn!("{:?} Failed to check pending tx: {}: {}", net, tx_hash, e);
+                continue;
             }
+        };
+        let res = match tx_status.status {
+            Status::Pending => DidPending::update_status(pool, net, &tx_hash, "pending").await,
+            Status::Proposed => DidPending::update_status(pool, net, &tx_hash, "proposed").await,
+            // the cells of an indexed block were promoted above, these were not indexed
+            Status::Committed
+                if tx_status
+                    .block_number
+                    .is_some_and(|number| number.value() < indexed) =>
+            {
+                log::info!(
+                    "{:?} Dropping pending tx: {}, committed in an indexed block without its DID cells",
+                    net,
+                    tx_hash
+                );
+                DidPending::drop_tx(pool, net, &tx_hash).await
+            }
+            // kept until the block is confirmed and indexed
+            Status::Committed => DidPending::update_status(pool, net, &tx_hash, "committed").await,
+            Status::Unknown if chrono::Utc::now() - seen_at < PENDING_UNKNOWN_GRACE => Ok(()),
+            Status::Unknown | Status::Rejected => {
+                log::info!(
+                    "{:?} Dropping pending tx: {}, {:?} {}",
+                    net,
+                    tx_hash,
+                    tx_status.status,
+                    tx_status.reason.unwrap_or_default()
+                );
+                DidPending::drop_tx(pool, net, &tx_hash).await
+            }
+        };
+        if let Err(e) = res {
+            log::error!("{:?} Failed to update pending tx: {}: {}", net, tx_hash, e);
         }
-        let mut dids = Vec::new();
-        let mut did_deletes = Vec::new();
-        for t in raw_tx_with_cell {
-            if let Tx::Grouped(tx) = t {
-                let tx_all = loop {
-                    let tx = rpc.get_transaction(url.clone(), &tx.tx_hash).await;
-                    if let Ok(tx) = tx {
-                        break tx.unwrap();
-                    }
-                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-                };
-                let header = loop {
-                    let header = rpc.get_header_by_number(url.clone(), tx.block_number).await;
-                    if let Ok(header) = header {
-                        break header;
-                    }
-                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-                };
-                for (typ, index) in tx.cells {
-                    match typ {
-                        CellType::Output => {
-                            let out_point =
-                                packed::OutPoint::new(tx.tx_hash.clone().into(), index.value());
-
-                            let lock = tx_all
-                                .inner
-                                .outputs
-                                .get(index.value() as usize)
-                                .unwrap()
-                                .lock
-                                .clone();
-                            let packed_lock: packed::Script = lock.into();
-                            let ckb_addr = calculate_address(&packed_lock, net.into());
-                            let lock_script_hash = blake160(&packed_lock.as_bytes());
-                            let cell_data = tx_all
-                                .inner
-                                .outputs_data
-                                .get(index.value() as usize)
-                                .unwrap();
-                            let didoc = match parse_didoc_cell(cell_data.as_bytes()) {
-                                Some(didoc) => didoc,
-                                None => {
-                                    log::warn!(
-                                        "{:?} Failed to parse DIDoc cell data at tx: {}, index: {}",
-                                        net,
-                                        tx.tx_hash,
-                                        index.value()
-                                    );
-                                    continue;
-                                }
-                            };
-
-                            let (handle, signing_key) = match check_did_doc(&didoc) {
-                                Some(handle) => handle,
-                                None => {
-                                    log::warn!(
-                                        "{:?} DIDoc check failed at tx: {}, index: {}",
-                                        net,
-                                        tx.tx_hash,
-                                        index.value()
-                                    );
-                                    continue;
-                                }
-                            };
-                            let type_script = tx_all
-                                .inner
-                                .outputs
-                                .get(index.value() as usize)
-                                .unwrap()
-                                .type_
-                                .as_ref()
-                                .unwrap();
-                            let web5_did = calculate_web5_did(&type_script.args.as_bytes()[..20]);
-                            let cell_data = {
-                                let data = tx_all
-                                    .inner
-                                    .outputs_data
-                                    .get(index.value() as usize)
-                                    .unwrap();
-                                faster_hex::hex_string(data.as_bytes())
-                            };
-
-                            // insert to db
-                            dids.push(crate::pg_write::DidWrite::new(
-                                web5_did,
-                                handle,
-                                signing_key,
-                                ckb_addr.to_string(),
-                                faster_hex::hex_string(tx.tx_hash.as_bytes()),
-                                faster_hex::hex_string(&tx.block_number.value().to_be_bytes()),
-                                faster_hex::hex_string(&out_point.as_bytes()),
-                                sqlx::types::Json(didoc),
-                                cell_data,
-                                faster_hex::hex_string(&lock_script_hash.as_bytes()),
-                                DateTime::from_timestamp_millis(
-                                    header.inner.timestamp.value() as i64
-                                )
-                                .unwrap(),
-                            ));
-                        }
-                        CellType::Input => {
-                            let out_point: packed::OutPoint = tx_all
-                                .inner
-                                .inputs
-                                .get(index.value() as usize)
-                                .unwrap()
-                                .previous_output
-                                .clone()
-                                .into();
-                            // change state to invalid
-                            did_deletes.push(crate::pg_write::DidDelete::new(
-                                faster_hex::hex_string(&out_point.as_bytes()),
-                                faster_hex::hex_string(tx.tx_hash.as_bytes()),
-                                DateTime::from_timestamp_millis(
-                                    header.inner.timestamp.value() as i64
-                                )
-                                .unwrap(),
-                            ));
+    }
+}
+
+/// DID cells created and consumed in one block window.
+struct BlockRange {
+    dids: Vec<DidWrite>,
+    did_deletes: Vec<DidDelete>,
+    /// DID cells that could not be indexed, with the reason.
+    rejected: Vec<DidRejected>,
+    /// Hashes of the blocks that contain DID transactions, by block number.
+    block_hashes: BTreeMap<u64, H256>,
+    /// Transactions and cells that could not be indexed, the rest of the window is.
+    skipped: Vec<IndexerError>,
+}
+
+/// Fetch and parse every DID transaction in `[start, end)`.
+async fn fetch_block_range(
+    rpc: &RpcClient,
+    endpoints: &Endpoints,
+    net: Network,
+    start: BlockNumber,
+    end: BlockNumber,
+) -> Result<BlockRange, IndexerError> {
+    let deployments = &crate::config().network(net).deployments;
+    // a transaction moving a cell from one deployment to another is found once per deployment
+    let mut txs: Vec<TxWithCells> = Vec::new();
+    let mut tx_positions: HashMap<H256, usize> = HashMap::new();
+    for deployment in deployments
+        .iter()
+        .filter(|deployment| deployment.start < end.value())
+    {
+        for tx in fetch_did_transactions(rpc, endpoints, net, deployment, start, end).await? {
+            match tx_positions.entry(tx.tx_hash.clone()) {
+                Entry::Occupied(position) => {
+                    let cells = &mut txs[*position.get()].cells;
+                    for cell in tx.cells {
+                        if !cells.contains(&cell) {
+                            cells.push(cell);
                         }
                     }
                 }
+                Entry::Vacant(position) => {
+                    position.insert(txs.len());
+                    txs.push(tx);
+                }
             }
         }
-        if !dids.is_empty() {
-            log::info!("{:?} Inserting {} DID entries", net, dids.len());
-            let mut conn = crate::get_pg_pool().begin().await.unwrap();
-            crate::pg_write::DidWrite::insert_batch(&mut conn, &dids, net)
-                .await
-                .expect("Failed to insert did writes");
-            conn.commit().await.unwrap();
-        }
-        if !did_deletes.is_empty() {
-            log::info!("{:?} Deleting {} DID entries", net, did_deletes.len());
-            let mut conn = crate::get_pg_pool().begin().await.unwrap();
-            crate::pg_write::DidDelete::delete_batch(&mut conn, &did_deletes, net)
-                .await
-                .expect("Failed to delete did deletes");
-            conn.commit().await.unwrap();
-        }
-        match net {
-            Network::Mainnet => {
-                crate::pg_write::global_cache().store(Arc::new(tip.block_number));
-            }
-            Network::Testnet => {
-                crate::pg_write::global_cache_testnet().store(Arc::new(tip_testnet.block_number));
+    }
+    if deployments.len() > 1 {
+        txs.sort_by_key(|tx| (tx.block_number.value(), tx.tx_index.value()));
+    }
+    let concurrency = crate::config().rpc_concurrency.max(1);
+    let batch_size = crate::config().rpc_batch_size.max(1);
+    // every DID transaction of a block shares its header, fetch each one once
+    let block_numbers = txs
+        .iter()
+        .map(|tx| tx.block_number.value())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(BlockNumber::from)
+        .collect::<Vec<_>>();
+    let headers = stream::iter(
+        block_numbers
+            .chunks(batch_size)
+            .map(<[_]>::to_vec)
+            .collect::<Vec<_>>(),
+    )
+    .map(|numbers| fetch_headers(rpc, endpoints, numbers))
+    .buffered(concurrency)
+    .try_collect::<Vec<_>>()
+    .await?
+    .into_iter()
+    .flatten()
+    .map(|header| (header.inner.number.value(), header))
+    .collect::<HashMap<_, _>>();
+    let tx_hashes = txs.iter().map(|tx| tx.tx_hash.clone()).collect::<Vec<_>>();
+    let tx_alls = stream::iter(
+        tx_hashes
+            .chunks(batch_size)
+            .map(<[_]>::to_vec)
+            .collect::<Vec<_>>(),
+    )
+    .map(|hashes| fetch_transactions(rpc, endpoints, hashes))
+    .buffered(concurrency)
+    .try_collect::<Vec<_>>()
+    .await?
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+
+    let mut dids = Vec::new();
+    let mut did_deletes = Vec::new();
+    let mut rejected = Vec::new();
+    let mut skipped = Vec::new();
+    let block_hashes = headers
+        .values()
+        .map(|header| (header.inner.number.value(), header.hash.clone()))
+        .collect::<BTreeMap<_, _>>();
+    for (tx, tx_all) in txs.into_iter().zip(tx_alls) {
+        let header = headers.get(&tx.block_number.value()).ok_or_else(|| {
+            RpcError::NotFound(format!("header of block {}", tx.block_number.value()))
+        })?;
+        let Some(created_at) =
+            DateTime::from_timestamp_millis(header.inner.timestamp.value() as i64)
+        else {
+            skipped.push(IndexerError::invalid_transaction(
+                &tx.tx_hash,
+                RejectReason::BlockTimestampOutOfRange,
+            ));
+            continue;
+        };
+        for (typ, index) in &tx.cells {
+            let res = match typ {
+                CellType::Output => {
+                    did_write(net, deployments, &tx, &tx_all, index.value(), created_at)
+                        .map(|did| dids.extend(did))
+                        .inspect_err(|e| {
+                            if let IndexerError::InvalidCell { index, reason, .. } = e {
+                                rejected
+                                    .push(did_rejected(&tx, &tx_all, *index, *reason, created_at));
+                            }
+                        })
+                }
+                CellType::Input => did_delete(&tx, &tx_all, index.value(), created_at)
+                    .map(|delete| did_deletes.push(delete)),
+            };
+            if let Err(e) = res {
+                skipped.push(e);
             }
         }
-        log::info!(
-            "Finished processing DID cells for {:?} up to block number {:?}",
-            net,
-            match net {
-                Network::Mainnet => tip.block_number.value(),
-                Network::Testnet => tip_testnet.block_number.value(),
-            }
+    }
+    Ok(BlockRange {
+        dids,
+        did_deletes,
+        rejected,
+        block_hashes,
+        skipped,
+    })
+}
+
+/// The row for output `index` of `tx`, `None` when it belongs to no deployment active at
+/// its block.
+fn did_write(
+    net: Network,
+    deployments: &[DidDeployment],
+    tx: &TxWithCells,
+    tx_all: &TransactionView,
+    index: u32,
+    created_at: DateTime<chrono::Utc>,
+) -> Result<Option<DidWrite>, IndexerError> {
+    let output = tx_all.inner.outputs.get(index as usize).ok_or_else(|| {
+        IndexerError::invalid_cell(&tx.tx_hash, index, RejectReason::NoSuchOutput)
+    })?;
+    let deployment = output.type_.as_ref().and_then(|script| {
+        deployments.iter().find(|deployment| {
+            deployment.matches(script) && deployment.is_active(tx.block_number.value())
+        })
+    });
+    // created before the deployment's activation or after its end
+    let Some(deployment) = deployment else {
+        return Ok(None);
+    };
+    let cell = parse_did_output(net, &tx.tx_hash, tx_all, index)?;
+
+    Ok(Some(DidWrite::new(
+        cell.did,
+        cell.handle,
+        cell.signing_key,
+        cell.ckb_address,
+        tx.tx_hash.clone(),
+        tx.block_number,
+        cell.outpoint,
+        sqlx::types::Json(cell.did_document),
+        cell.cell_data,
+        cell.lock_script_hash,
+        created_at,
+        deployment.name.clone(),
+    )))
+}
+
+/// The quarantine record of output `index` of `tx`, with whatever could be read from it.
+fn did_rejected(
+    tx: &TxWithCells,
+    tx_all: &TransactionView,
+    index: u32,
+    reason: RejectReason,
+    created_at: DateTime<chrono::Utc>,
+) -> DidRejected {
+    let did = tx_all
+        .inner
+        .outputs
+        .get(index as usize)
+        .and_then(|output| output.type_.as_ref())
+        .map(|script| script.args.as_bytes())
+        .filter(|args| args.len() >= 20)
+        .map(|args| calculate_web5_did(&args[..20]));
+    let cell_data = tx_all
+        .inner
+        .outputs_data
+        .get(index as usize)
+        .map(|data| data.as_bytes().to_vec());
+    DidRejected::new(
+        tx.tx_hash.clone(),
+        index,
+        tx.block_number,
+        did,
+        cell_data,
+        reason,
+        created_at,
+    )
+}
+
+/// The consumption of the cell spent by input `index` of `tx`.
+fn did_delete(
+    tx: &TxWithCells,
+    tx_all: &TransactionView,
+    index: u32,
+    consumed_at: DateTime<chrono::Utc>,
+) -> Result<DidDelete, IndexerError> {
+    let out_point: packed::OutPoint = tx_all
+        .inner
+        .inputs
+        .get(index as usize)
+        .ok_or_else(|| IndexerError::invalid_cell(&tx.tx_hash, index, RejectReason::NoSuchInput))?
+        .previous_output
+        .clone()
+        .into();
+    Ok(DidDelete::new(
+        out_point.as_slice().to_vec(),
+        tx.tx_hash.clone(),
+        consumed_at,
+        tx.block_number,
+    ))
+}
+
+/// Every transaction in `[start, end)` with a cell of `deployment`.
+async fn fetch_did_transactions(
+    rpc: &RpcClient,
+    endpoints: &Endpoints,
+    net: Network,
+    deployment: &DidDeployment,
+    start: BlockNumber,
+    end: BlockNumber,
+) -> Result<Vec<TxWithCells>, RpcError> {
+    let retry = &crate::config().rpc_retry;
+    let search_key = SearchKey {
+        script: deployment.script(ckb_jsonrpc_types::JsonBytes::default()),
+        script_type: ScriptType::Type,
+        filter: Some(SearchKeyFilter::block_range(start, end)),
+        with_data: None,
+        script_search_mode: Some(IndexerScriptSearchMode::Prefix),
+        group_by_transaction: Some(true),
+    };
+
+    let mut raw_tx_with_cell = Vec::new();
+    let mut after_cursor = None;
+    loop {
+        let result = retry
+            .retry(
+                format!("{:?} get_transactions of {}", net, deployment.name),
+                || {
+                    endpoints.call(|url| {
+                        rpc.get_transactions(
+                            url,
+                            search_key.clone(),
+                            Order::Asc,
+                            500.into(),
+                            after_cursor.clone(),
+                        )
+                    })
+                },
+            )
+            .await?;
+        let has_more = result.objects.len() == 500;
+        raw_tx_with_cell.extend(result.objects);
+        if !has_more {
+            break;
+        }
+        after_cursor = Some(result.last_cursor);
+    }
+    Ok(raw_tx_with_cell
+        .into_iter()
+        .filter_map(|t| match t {
+            Tx::Grouped(tx) => Some(tx),
+            Tx::Ungrouped(_) => None,
+        })
+        .collect())
+}
+
+/// The fields of a DID cell that do not depend on the block it is in.
+struct DidCell {
+    did: String,
+    handle: String,
+    signing_key: String,
+    ckb_address: String,
+    outpoint: Vec<u8>,
+    did_document: Web5DocumentData,
+    cell_data: Vec<u8>,
+    lock_script_hash: H160,
+}
+
+/// Parse output `index` of `tx` as a DID cell.
+fn parse_did_output(
+    net: Network,
+    tx_hash: &H256,
+    tx_all: &TransactionView,
+    index: u32,
+) -> Result<DidCell, IndexerError> {
+    let invalid = |reason| IndexerError::invalid_cell(tx_hash, index, reason);
+    let out_point = packed::OutPoint::new(tx_hash.clone().into(), index);
+
+    let output = tx_all
+        .inner
+        .outputs
+        .get(index as usize)
+        .ok_or_else(|| invalid(RejectReason::NoSuchOutput))?;
+    let packed_lock: packed::Script = output.lock.clone().into();
+    let ckb_addr = calculate_address(&packed_lock, net.into());
+    let lock_script_hash = blake160(&packed_lock.as_bytes());
+    let cell_data = tx_all
+        .inner
+        .outputs_data
+        .get(index as usize)
+        .ok_or_else(|| invalid(RejectReason::NoOutputData))?;
+    let didoc = parse_didoc_cell(cell_data.as_bytes())
+        .ok_or_else(|| invalid(RejectReason::MalformedDidoc))?;
+    let (handle, signing_key) =
+        check_did_doc(&didoc).ok_or_else(|| invalid(RejectReason::DidocCheckFailed))?;
+    let type_script = output
+        .type_
+        .as_ref()
+        .ok_or_else(|| invalid(RejectReason::NoTypeScript))?;
+    let args = type_script.args.as_bytes();
+    if args.len() < 20 {
+        return Err(invalid(RejectReason::ShortTypeArgs));
+    }
+    let web5_did = calculate_web5_did(&args[..20]);
+
+    Ok(DidCell {
+        did: web5_did,
+        handle,
+        signing_key,
+        ckb_address: ckb_addr.to_string(),
+        outpoint: out_point.as_slice().to_vec(),
+        did_document: didoc,
+        cell_data: cell_data.as_bytes().to_vec(),
+        lock_script_hash,
+    })
+}
+
+/// Fetch `numbers` in one batch, calls that failed inside the batch are retried one by one.
+async fn fetch_headers(
+    rpc: &RpcClient,
+    endpoints: &Endpoints,
+    numbers: Vec<BlockNumber>,
+) -> Result<Vec<HeaderView>, RpcError> {
+    let results = crate::config()
+        .rpc_retry
+        .retry("batch get_header_by_number", || {
+            endpoints.call(|url| rpc.batch_get_header_by_number(url, &numbers))
+        })
+        .await?;
+    let mut headers = Vec::with_capacity(numbers.len());
+    for (number, result) in numbers.into_iter().zip(results) {
+        headers.push(match result {
+            Ok(header) => header,
+            Err(_) => fetch_header(rpc, endpoints, number).await?,
+        });
+    }
+    Ok(headers)
+}
+
+/// Fetch `hashes` in one batch, calls that failed inside the batch are retried one by one.
+async fn fetch_transactions(
+    rpc: &RpcClient,
+    endpoints: &Endpoints,
+    hashes: Vec<H256>,
+) -> Result<Vec<TransactionView>, RpcError> {
+    let results = crate::config()
+        .rpc_retry
+        .retry("batch get_transaction", || {
+            endpoints.call(|url| rpc.batch_get_transaction(url, &hashes))
+        })
+        .await?;
+    let mut txs = Vec::with_capacity(hashes.len());
+    for (hash, result) in hashes.into_iter().zip(results) {
+        txs.push(match result {
+            Ok(Some(tx)) => tx,
+            _ => fetch_transaction(rpc, endpoints, hash).await?,
+        });
+    }
+    Ok(txs)
+}
+
+async fn fetch_header(
+    rpc: &RpcClient,
+    endpoints: &Endpoints,
+    number: BlockNumber,
+) -> Result<HeaderView, RpcError> {
+    crate::config()
+        .rpc_retry
+        .retry(format!("get_header_by_number {}", number.value()), || {
+            endpoints.call(|url| rpc.get_header_by_number(url, number))
+        })
+        .await
+}
+
+async fn fetch_transaction(
+    rpc: &RpcClient,
+    endpoints: &Endpoints,
+    hash: H256,
+) -> Result<TransactionView, RpcError> {
+    crate::config()
+        .rpc_retry
+        .retry(format!("get_transaction {}", hash), || {
+            endpoints.call(|url| async {
+                rpc.get_transaction(url, &hash)
+                    .await?
+                    .ok_or_else(|| RpcError::NotFound(format!("transaction {}", hash)))
+            })
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The canonical chain of the tests: block `n` has hash `[n; 32]`.
+    fn canonical(number: u64) -> H256 {
+        H256([number as u8; 32])
+    }
+
+    fn checkpoint(number: u64, hash: H256) -> (BlockNumber, H256) {
+        (number.into(), hash)
+    }
+
+    /// Run `find_fork_point` against the canonical chain, returns the result and the
+    /// block numbers that were looked up.
+    async fn check(tip: u64, checkpoints: &[(BlockNumber, H256)]) -> (ForkCheck, Vec<u64>) {
+        let mut looked_up = Vec::new();
+        let res = find_fork_point(tip.into(), checkpoints, |number| {
+            looked_up.push(number.value());
+            async move { Ok(canonical(number.value())) }
+        })
+        .await
+        .unwrap();
+        (res, looked_up)
+    }
+
+    #[tokio::test]
+    async fn newest_checkable_checkpoint_on_chain_is_canonical() {
+        let checkpoints = [
+            checkpoint(30, H256([0xff; 32])),
+            checkpoint(20, canonical(20)),
+            checkpoint(10, canonical(10)),
+        ];
+        // 30 is above the tip and not compared
+        assert_eq!(
+            check(25, &checkpoints).await,
+            (ForkCheck::Canonical, vec![20])
+        );
+        assert_eq!(check(25, &[]).await, (ForkCheck::Canonical, vec![]));
+    }
+
+    #[tokio::test]
+    async fn lower_checkpoint_on_chain_is_the_fork_point() {
+        let checkpoints = [
+            checkpoint(30, H256([0xff; 32])),
+            checkpoint(20, H256([0xfe; 32])),
+            checkpoint(10, canonical(10)),
+            checkpoint(5, canonical(5)),
+        ];
+        assert_eq!(
+            check(40, &checkpoints).await,
+            (ForkCheck::Fork(10u64.into()), vec![30, 20, 10])
+        );
+    }
+
+    #[tokio::test]
+    async fn checkpoints_above_the_tip_are_undecided() {
+        let checkpoints = [
+            checkpoint(30, H256([0xff; 32])),
+            checkpoint(20, canonical(20)),
+        ];
+        assert_eq!(
+            check(19, &checkpoints).await,
+            (ForkCheck::Undecided, vec![])
+        );
+    }
+
+    #[tokio::test]
+    async fn no_checkpoint_on_chain_is_too_deep() {
+        let checkpoints = [
+            checkpoint(30, H256([0xff; 32])),
+            checkpoint(20, H256([0xfe; 32])),
+            checkpoint(10, H256([0xfd; 32])),
+        ];
+        assert_eq!(
+            check(30, &checkpoints).await,
+            (ForkCheck::TooDeep, vec![30, 20, 10])
         );
     }
 }
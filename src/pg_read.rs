@@ -1,23 +1,89 @@
-use crate::{http_server::Params, types::Web5DocumentData};
-use sqlx::{FromRow, Pool, Postgres, types::Json};
+use crate::{
+    Network,
+    http_server::{Params, RejectedParams},
+    pg_write::IndexerState,
+    types::Web5DocumentData,
+};
+use sqlx::{Encode, FromRow, Pool, Postgres, Type, types::Json};
 
 const PAGE_SIZE: usize = 500;
 
+/// `0x` prefixed hex of a `bytea` column.
+fn hex(bytes: &[u8]) -> String {
+    format!("0x{}", faster_hex::hex_string(bytes))
+}
+
+/// `0x` prefixed, zero padded big-endian hex, the format block numbers had when they were
+/// stored as text.
+fn block_number_hex(number: i64) -> String {
+    format!("0x{:016x}", number)
+}
+
+/// Bytes of a hex parameter with or without `0x`, `None` if it is not hex.
+fn decode_hex(raw: &str) -> Option<Vec<u8>> {
+    let raw = raw.trim_start_matches("0x");
+    let mut bytes = vec![0; raw.len() / 2];
+    faster_hex::hex_decode(raw.as_bytes(), &mut bytes).ok()?;
+    Some(bytes)
+}
+
+/// One page of the records whose `column` is `$1`: the current document of every matching
+/// DID, or every indexed cell including consumed ones when `history` is asked for.
+fn lookup_sql(params: &Params, column: &str) -> String {
+    let page_size = std::cmp::min(params.page_size.unwrap_or(PAGE_SIZE), PAGE_SIZE);
+    let offset = params.page.saturating_mul(page_size);
+    let (table, consumed) = if params.history {
+        (
+            params.net.did(),
+            "consumed_tx, consumed_at, prev_outpoint, next_outpoint",
+        )
+    } else {
+        (
+            params.net.current(),
+            "NULL::bytea AS consumed_tx, NULL::timestamptz AS consumed_at, prev_outpoint, NULL::bytea AS next_outpoint",
+        )
+    };
+    format!(
+        r#"SELECT did, handle, signing_key, ckb_address, tx_hash, block_number, outpoint, did_document, cell_data, {consumed}, created_at, lock_script_hash, deployment
+        FROM {table}
+        WHERE {column} = $1 ORDER BY created_at DESC LIMIT {page_size} OFFSET {offset}"#
+    )
+}
+
 #[derive(FromRow)]
 pub(crate) struct DidRead {
     did: String,
     handle: String,
     signing_key: String,
     ckb_address: String,
-    tx_hash: String,
-    block_number: String,
-    outpoint: String,
-    cell_data: String,
-    lock_script_hash: String,
+    tx_hash: Vec<u8>,
+    block_number: i64,
+    outpoint: Vec<u8>,
+    cell_data: Vec<u8>,
+    lock_script_hash: Vec<u8>,
     did_document: Json<Web5DocumentData>,
     created_at: chrono::DateTime<chrono::Utc>,
-    consumed_tx: Option<String>,
+    consumed_tx: Option<Vec<u8>>,
     consumed_at: Option<chrono::DateTime<chrono::Utc>>,
+    deployment: Option<String>,
+    prev_outpoint: Option<Vec<u8>>,
+    next_outpoint: Option<Vec<u8>>,
+}
+
+#[derive(FromRow)]
+pub(crate) struct DidPendingRead {
+    did: String,
+    handle: String,
+    signing_key: String,
+    ckb_address: String,
+    tx_hash: Vec<u8>,
+    outpoint: Vec<u8>,
+    cell_data: Vec<u8>,
+    lock_script_hash: Vec<u8>,
+    did_document: Json<Web5DocumentData>,
+    deployment: Option<String>,
+    tx_status: String,
+    created_at: chrono::DateTime<chrono::Utc>,
 }
 
 impl DidRead {
@@ -25,16 +91,11 @@ impl DidRead {
         conn: &Pool<Postgres>,
         params: Params,
     ) -> sqlx::Result<(Vec<serde_json::Value>, usize)> {
-        let page_size = std::cmp::min(params.page_size.unwrap_or(PAGE_SIZE), PAGE_SIZE);
-        let offset = params.page.saturating_mul(page_size);
-        let sql = format!(
-            r#"SELECT did, handle, signing_key, ckb_address, tx_hash, block_number, outpoint, did_document, cell_data, consumed_tx, created_at, consumed_at, lock_script_hash
-            FROM {} 
-            WHERE did = $1 ORDER BY created_at DESC LIMIT {page_size} OFFSET {offset}"#,
-            params.net.did()
-        );
+        let sql = lookup_sql(&params, "did");
 
-        let record: Vec<serde_json::Value> = Self::fetch_doc(conn, &sql, &params.name).await?;
+        let mut record =
+            Self::fetch_pending(conn, params.net, params.page, "did", &params.name).await?;
+        record.extend(Self::fetch_doc(conn, params.net, &sql, &params.name).await?);
         Ok((record, params.page.saturating_add(1)))
     }
 
@@ -42,16 +103,11 @@ impl DidRead {
         conn: &Pool<Postgres>,
         params: Params,
     ) -> sqlx::Result<(Vec<serde_json::Value>, usize)> {
-        let page_size = std::cmp::min(params.page_size.unwrap_or(PAGE_SIZE), PAGE_SIZE);
-        let offset = params.page.saturating_mul(page_size);
-        let sql = format!(
-            r#"SELECT did, handle, signing_key, ckb_address, tx_hash, block_number, outpoint, did_document, cell_data, consumed_tx, created_at, consumed_at, lock_script_hash
-            FROM {} 
-            WHERE ckb_address = $1 ORDER BY created_at DESC LIMIT {page_size} OFFSET {offset}"#,
-            params.net.did()
-        );
+        let sql = lookup_sql(&params, "ckb_address");
 
-        let record: Vec<serde_json::Value> = Self::fetch_doc(conn, &sql, &params.name).await?;
+        let mut record =
+            Self::fetch_pending(conn, params.net, params.page, "ckb_address", &params.name).await?;
+        record.extend(Self::fetch_doc(conn, params.net, &sql, &params.name).await?);
         Ok((record, params.page.saturating_add(1)))
     }
 
@@ -59,16 +115,11 @@ impl DidRead {
         conn: &Pool<Postgres>,
         params: Params,
     ) -> sqlx::Result<(Vec<serde_json::Value>, usize)> {
-        let page_size = std::cmp::min(params.page_size.unwrap_or(PAGE_SIZE), PAGE_SIZE);
-        let offset = params.page.saturating_mul(page_size);
-        let sql = format!(
-            r#"SELECT did, handle, signing_key, ckb_address, tx_hash, block_number, outpoint, did_document, cell_data, consumed_tx, created_at, consumed_at, lock_script_hash
-            FROM {} 
-            WHERE signing_key = $1 ORDER BY created_at DESC LIMIT {page_size} OFFSET {offset}"#,
-            params.net.did()
-        );
+        let sql = lookup_sql(&params, "signing_key");
 
-        let record: Vec<serde_json::Value> = Self::fetch_doc(conn, &sql, &params.name).await?;
+        let mut record =
+            Self::fetch_pending(conn, params.net, params.page, "signing_key", &params.name).await?;
+        record.extend(Self::fetch_doc(conn, params.net, &sql, &params.name).await?);
         Ok((record, params.page.saturating_add(1)))
     }
 
@@ -76,46 +127,134 @@ impl DidRead {
         conn: &Pool<Postgres>,
         params: Params,
     ) -> sqlx::Result<(Vec<serde_json::Value>, usize)> {
-        let page_size = std::cmp::min(params.page_size.unwrap_or(PAGE_SIZE), PAGE_SIZE);
-        let offset = params.page.saturating_mul(page_size);
-        let sql = format!(
-            r#"SELECT did, handle, signing_key, ckb_address, tx_hash, block_number, outpoint, did_document, cell_data, consumed_tx, created_at, consumed_at, lock_script_hash
-            FROM {} 
-            WHERE handle = $1 ORDER BY created_at DESC LIMIT {page_size} OFFSET {offset}"#,
-            params.net.did()
-        );
+        let sql = lookup_sql(&params, "handle");
 
-        let record: Vec<serde_json::Value> = Self::fetch_doc(conn, &sql, &params.name).await?;
+        let mut record =
+            Self::fetch_pending(conn, params.net, params.page, "handle", &params.name).await?;
+        record.extend(Self::fetch_doc(conn, params.net, &sql, &params.name).await?);
         Ok((record, params.page.saturating_add(1)))
     }
 
     pub async fn fetch_by_lock_script_hash(
         conn: &Pool<Postgres>,
         params: Params,
+    ) -> sqlx::Result<(Vec<serde_json::Value>, usize)> {
+        let sql = lookup_sql(&params, "lock_script_hash");
+        let Some(hash) = decode_hex(&params.name) else {
+            return Ok((Vec::new(), params.page.saturating_add(1)));
+        };
+        let mut record =
+            Self::fetch_pending(conn, params.net, params.page, "lock_script_hash", &hash[..])
+                .await?;
+        record.extend(Self::fetch_doc(conn, params.net, &sql, &hash[..]).await?);
+        Ok((record, params.page.saturating_add(1)))
+    }
+
+    /// Every version of `params.did` oldest first, following the links from each cell to the
+    /// cell that replaced it.
+    pub async fn fetch_history(
+        conn: &Pool<Postgres>,
+        params: Params,
     ) -> sqlx::Result<(Vec<serde_json::Value>, usize)> {
         let page_size = std::cmp::min(params.page_size.unwrap_or(PAGE_SIZE), PAGE_SIZE);
         let offset = params.page.saturating_mul(page_size);
+        // a cell whose predecessor was never indexed starts a chain of its own
         let sql = format!(
-            r#"SELECT did, handle, signing_key, ckb_address, tx_hash, block_number, outpoint, did_document, cell_data, consumed_tx, created_at, consumed_at, lock_script_hash
-            FROM {} 
-            WHERE lock_script_hash = $1 ORDER BY created_at DESC LIMIT {page_size} OFFSET {offset}"#,
-            params.net.did()
+            r#"WITH RECURSIVE chain AS (
+                SELECT d.*, 0 AS depth FROM {did} d WHERE did = $1 AND prev_outpoint IS NULL
+                UNION ALL
+                SELECT d.*, c.depth + 1 FROM {did} d JOIN chain c ON d.outpoint = c.next_outpoint
+            )
+            SELECT did, handle, signing_key, ckb_address, tx_hash, block_number, outpoint, did_document, cell_data, consumed_tx, created_at, consumed_at, lock_script_hash, deployment, prev_outpoint, next_outpoint
+            FROM chain ORDER BY block_number, depth LIMIT {page_size} OFFSET {offset}"#,
+            did = params.net.did()
         );
-        let name = if params.name.starts_with("0x") {
-            params.name[2..].to_string()
-        } else {
-            params.name
-        };
-        let record: Vec<serde_json::Value> = Self::fetch_doc(conn, &sql, &name).await?;
+        let record = Self::fetch_doc(conn, params.net, &sql, &params.name).await?;
         Ok((record, params.page.saturating_add(1)))
     }
 
-    async fn fetch_doc(
+    /// The live cell of `did`, if it was created and not consumed yet.
+    pub async fn fetch_current(
+        conn: &Pool<Postgres>,
+        net: Network,
+        did: &str,
+    ) -> sqlx::Result<Option<serde_json::Value>> {
+        let sql = format!(
+            r#"SELECT did, handle, signing_key, ckb_address, tx_hash, block_number, outpoint, did_document, cell_data, NULL::bytea AS consumed_tx, created_at, NULL::timestamptz AS consumed_at, lock_script_hash, deployment, prev_outpoint, NULL::bytea AS next_outpoint
+            FROM {}
+            WHERE did = $1"#,
+            net.current()
+        );
+        Ok(Self::fetch_doc(conn, net, &sql, did).await?.pop())
+    }
+
+    /// DID cells of transactions still in the pool whose `column` is `key`, listed ahead
+    /// of the first page.
+    async fn fetch_pending<K>(
+        conn: &Pool<Postgres>,
+        net: Network,
+        page: usize,
+        column: &str,
+        key: K,
+    ) -> sqlx::Result<Vec<serde_json::Value>>
+    where
+        K: for<'q> Encode<'q, Postgres> + Type<Postgres> + Send,
+    {
+        if page != 0 {
+            return Ok(Vec::new());
+        }
+        let sql = format!(
+            r#"SELECT did, handle, signing_key, ckb_address, tx_hash, outpoint, did_document, cell_data, lock_script_hash, deployment, tx_status, created_at
+            FROM {} p
+            WHERE {column} = $1 AND NOT EXISTS (SELECT 1 FROM {} d WHERE d.outpoint = p.outpoint)
+            ORDER BY created_at DESC LIMIT {PAGE_SIZE}"#,
+            net.pending(),
+            net.did()
+        );
+        Ok(sqlx::query_as::<_, DidPendingRead>(&sql)
+            .bind(key)
+            .fetch_all(conn)
+            .await?
+            .iter()
+            .map(|r| {
+                serde_json::json!({
+                    "did": r.did,
+                    "handle": r.handle,
+                    "signing_key": r.signing_key,
+                    "ckb_address": r.ckb_address,
+                    "tx_hash": hex(&r.tx_hash),
+                    "block_number": null,
+                    "outpoint": hex(&r.outpoint),
+                    "cell_data": hex(&r.cell_data),
+                    "lock_script_hash": hex(&r.lock_script_hash),
+                    "did_document": r.did_document,
+                    "created_at": r.created_at.to_rfc3339(),
+                    "consumed_tx": null,
+                    "consumed_at": null,
+                    "deployment": r.deployment,
+                    "prev_outpoint": null,
+                    "next_outpoint": null,
+                    "status": "pending",
+                    "tx_status": r.tx_status,
+                    "confirmations": 0,
+                })
+            })
+            .collect())
+    }
+
+    async fn fetch_doc<K>(
         conn: &Pool<Postgres>,
+        net: Network,
         sql: &str,
-        key: &str,
-    ) -> sqlx::Result<Vec<serde_json::Value>> {
-        Ok(sqlx::query_as::<_, DidRead>(&sql)
+        key: K,
+    ) -> sqlx::Result<Vec<serde_json::Value>>
+    where
+        K: for<'q> Encode<'q, Postgres> + Type<Postgres> + Send,
+    {
+        let tip = IndexerState::fetch(conn, net)
+            .await?
+            .and_then(|state| state.tip_block_number);
+        Ok(sqlx::query_as::<_, DidRead>(sql)
             .bind(key)
             .fetch_all(conn)
             .await?
@@ -126,17 +265,105 @@ impl DidRead {
                     "handle": r.handle,
                     "signing_key": r.signing_key,
                     "ckb_address": r.ckb_address,
-                    "tx_hash": format!("0x{}", r.tx_hash),
-                    "block_number": format!("0x{}", r.block_number),
-                    "outpoint": format!("0x{}", r.outpoint),
-                    "cell_data": format!("0x{}", r.cell_data),
-                    "lock_script_hash": format!("0x{}", r.lock_script_hash),
+                    "tx_hash": hex(&r.tx_hash),
+                    "block_number": block_number_hex(r.block_number),
+                    "outpoint": hex(&r.outpoint),
+                    "cell_data": hex(&r.cell_data),
+                    "lock_script_hash": hex(&r.lock_script_hash),
                     "did_document": r.did_document,
                     "created_at": r.created_at.to_rfc3339(),
-                    "consumed_tx": r.consumed_tx.as_ref().map(|tx| format!("0x{}", tx)),
+                    "consumed_tx": r.consumed_tx.as_deref().map(hex),
                     "consumed_at": r.consumed_at.map(|dt| dt.to_rfc3339()),
+                    "deployment": r.deployment,
+                    "prev_outpoint": r.prev_outpoint.as_deref().map(hex),
+                    "next_outpoint": r.next_outpoint.as_deref().map(hex),
+                    "status": "committed",
+                    "confirmations": tip
+                        .map(|tip| tip.value().saturating_sub(r.block_number as u64) + 1),
                 })
             })
             .collect())
     }
 }
+
+#[derive(FromRow)]
+pub(crate) struct DidRejectedRead {
+    tx_hash: Vec<u8>,
+    output_index: i32,
+    block_number: i64,
+    did: Option<String>,
+    cell_data: Option<Vec<u8>>,
+    reason: String,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl DidRejectedRead {
+    /// Latest rejections first, narrowed down to one DID or one transaction when given.
+    pub async fn fetch(
+        conn: &Pool<Postgres>,
+        params: RejectedParams,
+    ) -> sqlx::Result<(Vec<serde_json::Value>, usize)> {
+        let page_size = std::cmp::min(params.page_size.unwrap_or(PAGE_SIZE), PAGE_SIZE);
+        let offset = params.page.saturating_mul(page_size);
+        let tx_hash = match params.tx_hash.as_deref().map(decode_hex) {
+            Some(None) => return Ok((Vec::new(), params.page.saturating_add(1))),
+            Some(Some(hash)) => Some(hash),
+            None => None,
+        };
+        let sql = format!(
+            r#"SELECT tx_hash, output_index, block_number, did, cell_data, reason, created_at
+            FROM {}
+            WHERE ($1::text IS NULL OR did = $1) AND ($2::bytea IS NULL OR tx_hash = $2)
+            ORDER BY block_number DESC, tx_hash, output_index LIMIT {page_size} OFFSET {offset}"#,
+            params.net.rejected()
+        );
+
+        let record = sqlx::query_as::<_, DidRejectedRead>(&sql)
+            .bind(&params.did)
+            .bind(&tx_hash)
+            .fetch_all(conn)
+            .await?
+            .iter()
+            .map(|r| {
+                serde_json::json!({
+                    "tx_hash": hex(&r.tx_hash),
+                    "index": r.output_index,
+                    "block_number": block_number_hex(r.block_number),
+                    "did": r.did,
+                    "cell_data": r.cell_data.as_deref().map(hex),
+                    "reason": r.reason,
+                    "created_at": r.created_at.to_rfc3339(),
+                })
+            })
+            .collect();
+        Ok((record, params.page.saturating_add(1)))
+    }
+}
+
+/// The current document of `did` on `net`, in the shape of a lookup record.
+pub async fn resolve_did(
+    conn: &Pool<Postgres>,
+    net: Network,
+    did: &str,
+) -> sqlx::Result<Option<serde_json::Value>> {
+    DidRead::fetch_current(conn, net, did).await
+}
+
+/// Progress of the indexer of `net`, as returned by `/indexer_state`.
+pub async fn indexer_status(
+    conn: &Pool<Postgres>,
+    net: Network,
+) -> sqlx::Result<serde_json::Value> {
+    let state = IndexerState::fetch(conn, net).await?;
+    let sql = format!("SELECT count(*) FROM {}", net.orphan_consumptions());
+    let orphan_consumptions: i64 = sqlx::query_scalar(&sql).fetch_one(conn).await?;
+    Ok(serde_json::json!({
+        "network": net.name(),
+        "block_number": state.as_ref().map(|s| s.block_number.value()),
+        "block_hash": state.as_ref().and_then(|s| s.block_hash.as_ref()).map(|h| hex(h.as_bytes())),
+        "tip_block_number": state.as_ref().and_then(|s| s.tip_block_number).map(|n| n.value()),
+        "confirmations": crate::config().network(net).confirmations,
+        "updated_at": state.as_ref().map(|s| s.updated_at.to_rfc3339()),
+        "orphan_consumptions": orphan_consumptions,
+    }))
+}
@@ -1,9 +1,13 @@
-use crate::{Network, types::Web5DocumentData};
+use crate::{IndexerTip, Network, RejectReason, types::Web5DocumentData};
 use ckb_jsonrpc_types::BlockNumber;
-use sqlx::{PgConnection, QueryBuilder, types::Json};
+use ckb_types::{H160, H256};
+use sqlx::{PgConnection, Pool, Postgres, QueryBuilder, Row, types::Json};
 
 use arc_swap::ArcSwap;
-use std::sync::{Arc, LazyLock};
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock},
+};
 
 pub(crate) fn global_cache() -> &'static ArcSwap<BlockNumber> {
     static GLOBAL_CACHE: LazyLock<ArcSwap<BlockNumber>> =
@@ -17,33 +21,84 @@ pub(crate) fn global_cache_testnet() -> &'static ArcSwap<BlockNumber> {
     &GLOBAL_CACHE
 }
 
+pub(crate) fn global_cache_of(net: Network) -> &'static ArcSwap<BlockNumber> {
+    static GLOBAL_CACHE_CUSTOM: LazyLock<HashMap<&'static str, ArcSwap<BlockNumber>>> =
+        LazyLock::new(|| {
+            crate::config()
+                .custom
+                .iter()
+                .map(|network| (network.name.as_str(), ArcSwap::new(Arc::new(0.into()))))
+                .collect()
+        });
+    match net {
+        Network::Mainnet => global_cache(),
+        Network::Testnet => global_cache_testnet(),
+        Network::Custom(name) => &GLOBAL_CACHE_CUSTOM[name],
+    }
+}
+
+/// How many blocks of checkpoints are kept below the tip, deeper reorgs are not expected.
+const CHECKPOINT_DEPTH: u64 = 1000;
+
+/// Most parameters Postgres accepts in one statement, multi-row inserts are split into
+/// chunks of `BIND_LIMIT / columns` rows.
+const BIND_LIMIT: usize = u16::MAX as usize;
+
+/// Bytes buffered before they are sent to a running `COPY`.
+const COPY_CHUNK_SIZE: usize = 1 << 20;
+
+const DID_COLUMNS: &str = "did, handle, signing_key, ckb_address, tx_hash, block_number, outpoint, did_document, cell_data, lock_script_hash, created_at, deployment";
+
+/// Re-indexing a range rewrites what is parsed from the cell, consumptions are kept.
+const DID_UPSERT: &str = " ON CONFLICT (outpoint) DO UPDATE SET did = EXCLUDED.did, handle = EXCLUDED.handle, signing_key = EXCLUDED.signing_key, \
+    ckb_address = EXCLUDED.ckb_address, tx_hash = EXCLUDED.tx_hash, block_number = EXCLUDED.block_number, did_document = EXCLUDED.did_document, \
+    cell_data = EXCLUDED.cell_data, lock_script_hash = EXCLUDED.lock_script_hash, created_at = EXCLUDED.created_at, deployment = EXCLUDED.deployment";
+
+/// Block numbers are stored as `bigint`, far above any reachable height.
+pub(crate) fn block_number_to_sql(number: BlockNumber) -> i64 {
+    number.value() as i64
+}
+
+pub(crate) fn block_number_from_sql(number: i64) -> BlockNumber {
+    BlockNumber::from(number as u64)
+}
+
+pub(crate) fn hash_from_sql(bytes: &[u8]) -> Option<H256> {
+    H256::from_slice(bytes).ok()
+}
+
 pub(crate) struct DidWrite {
     did: String,
     handle: String,
     signing_key: String,
     ckb_address: String,
-    tx_hash: String,
-    block_number: String,
-    outpoint: String,
+    tx_hash: H256,
+    block_number: BlockNumber,
+    /// Molecule encoded `OutPoint`.
+    outpoint: Vec<u8>,
     did_document: Json<Web5DocumentData>,
-    cell_data: String,
-    lock_script_hash: String,
+    cell_data: Vec<u8>,
+    /// blake160 of the lock script.
+    lock_script_hash: H160,
     created_at: chrono::DateTime<chrono::Utc>,
+    deployment: String,
 }
 
 impl DidWrite {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         did: String,
         handle: String,
         signing_key: String,
         ckb_address: String,
-        tx_hash: String,
-        block_number: String,
-        outpoint: String,
+        tx_hash: H256,
+        block_number: BlockNumber,
+        outpoint: Vec<u8>,
         did_document: Json<Web5DocumentData>,
-        cell_data: String,
-        lock_script_hash: String,
+        cell_data: Vec<u8>,
+        lock_script_hash: H160,
         created_at: chrono::DateTime<chrono::Utc>,
+        deployment: String,
     ) -> Self {
         Self {
             did,
@@ -57,9 +112,12 @@ impl DidWrite {
             cell_data,
             lock_script_hash,
             created_at,
+            deployment,
         }
     }
 
+    /// Upsert `dids`, with binary `COPY` once there are at least `INDEXER_COPY_MIN_ROWS`
+    /// of them.
     pub async fn insert_batch(
         conn: &mut PgConnection,
         dids: &[DidWrite],
@@ -68,72 +126,978 @@ impl DidWrite {
         if dids.is_empty() {
             return Ok(());
         }
+        let copy_min_rows = crate::config().copy_min_rows;
+        if copy_min_rows > 0 && dids.len() >= copy_min_rows {
+            return Self::copy_batch(conn, dids, net).await;
+        }
+
+        for chunk in dids.chunks(BIND_LIMIT / 12) {
+            let sql = format!("INSERT INTO {} ({DID_COLUMNS}) ", net.did());
+            let mut query_builder: QueryBuilder<'_, sqlx::Postgres> = QueryBuilder::new(sql);
+            query_builder.push_values(chunk, |mut b, did_write| {
+                b.push_bind(&did_write.did)
+                    .push_bind(&did_write.handle)
+                    .push_bind(&did_write.signing_key)
+                    .push_bind(&did_write.ckb_address)
+                    .push_bind(did_write.tx_hash.as_bytes())
+                    .push_bind(block_number_to_sql(did_write.block_number))
+                    .push_bind(&did_write.outpoint)
+                    .push_bind(&did_write.did_document)
+                    .push_bind(&did_write.cell_data)
+                    .push_bind(did_write.lock_script_hash.as_bytes())
+                    .push_bind(did_write.created_at)
+                    .push_bind(&did_write.deployment);
+            });
+            query_builder.push(DID_UPSERT);
+            query_builder.build().execute(&mut *conn).await?;
+        }
+        Ok(())
+    }
+
+    /// Load `dids` with `COPY ... FROM STDIN (FORMAT binary)` into a staging table, then
+    /// upsert them from there. Must run inside a transaction, the staging table is dropped
+    /// at commit.
+    async fn copy_batch(
+        conn: &mut PgConnection,
+        dids: &[DidWrite],
+        net: Network,
+    ) -> Result<(), sqlx::Error> {
         let sql = format!(
-            "INSERT INTO {} (did, handle, signing_key, ckb_address, tx_hash, block_number, outpoint, did_document, cell_data, lock_script_hash, created_at) ",
+            "CREATE TEMP TABLE did_copy (LIKE {} INCLUDING DEFAULTS) ON COMMIT DROP",
             net.did()
         );
+        sqlx::query(&sql).execute(&mut *conn).await?;
 
-        let mut query_builder: QueryBuilder<'_, sqlx::Postgres> = QueryBuilder::new(sql);
-        query_builder.push_values(dids.iter().take(65535 / 10), |mut b, did_write| {
-            b.push_bind(&did_write.did)
-                .push_bind(&did_write.handle)
-                .push_bind(&did_write.signing_key)
-                .push_bind(&did_write.ckb_address)
-                .push_bind(&did_write.tx_hash)
-                .push_bind(&did_write.block_number)
-                .push_bind(&did_write.outpoint)
-                .push_bind(&did_write.did_document)
-                .push_bind(&did_write.cell_data)
-                .push_bind(&did_write.lock_script_hash)
-                .push_bind(&did_write.created_at);
-        });
+        let mut copy = conn
+            .copy_in_raw(&format!(
+                "COPY did_copy ({DID_COLUMNS}) FROM STDIN (FORMAT binary)"
+            ))
+            .await?;
+        let mut rows = BinaryCopy::new();
+        for did_write in dids {
+            let did_document = serde_json::to_vec(&did_write.did_document.0)
+                .map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+            rows.row(12)
+                .text(&did_write.did)
+                .text(&did_write.handle)
+                .text(&did_write.signing_key)
+                .text(&did_write.ckb_address)
+                .bytes(did_write.tx_hash.as_bytes())
+                .bigint(block_number_to_sql(did_write.block_number))
+                .bytes(&did_write.outpoint)
+                .jsonb(&did_document)
+                .bytes(&did_write.cell_data)
+                .bytes(did_write.lock_script_hash.as_bytes())
+                .timestamptz(did_write.created_at)
+                .text(&did_write.deployment);
+            if rows.len() >= COPY_CHUNK_SIZE {
+                copy.send(rows.take()).await?;
+            }
+        }
+        copy.send(rows.finish()).await?;
+        copy.finish().await?;
 
-        query_builder.build().execute(conn).await?;
+        let sql = format!(
+            "INSERT INTO {} ({DID_COLUMNS}) SELECT {DID_COLUMNS} FROM did_copy{DID_UPSERT}",
+            net.did()
+        );
+        sqlx::query(&sql).execute(&mut *conn).await?;
+        sqlx::query("DROP TABLE did_copy")
+            .execute(&mut *conn)
+            .await?;
         Ok(())
     }
 }
 
+/// Rows in the binary format of `COPY ... FROM STDIN (FORMAT binary)`.
+struct BinaryCopy {
+    buf: Vec<u8>,
+}
+
+impl BinaryCopy {
+    fn new() -> Self {
+        let mut buf = b"PGCOPY\n\xff\r\n\0".to_vec();
+        // flags and header extension length
+        buf.extend_from_slice(&0i32.to_be_bytes());
+        buf.extend_from_slice(&0i32.to_be_bytes());
+        Self { buf }
+    }
+
+    fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// The rows written so far, to be sent before the next ones.
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.buf.extend_from_slice(&(-1i16).to_be_bytes());
+        self.buf
+    }
+
+    fn row(&mut self, fields: i16) -> &mut Self {
+        self.buf.extend_from_slice(&fields.to_be_bytes());
+        self
+    }
+
+    fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.buf
+            .extend_from_slice(&(value.len() as i32).to_be_bytes());
+        self.buf.extend_from_slice(value);
+        self
+    }
+
+    fn text(&mut self, value: &str) -> &mut Self {
+        self.bytes(value.as_bytes())
+    }
+
+    fn bigint(&mut self, value: i64) -> &mut Self {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    /// Microseconds since 2000-01-01 UTC.
+    fn timestamptz(&mut self, value: chrono::DateTime<chrono::Utc>) -> &mut Self {
+        const POSTGRES_EPOCH: i64 = 946_684_800_000_000;
+        self.bigint(value.timestamp_micros() - POSTGRES_EPOCH)
+    }
+
+    /// `jsonb` is its text prefixed with the format version.
+    fn jsonb(&mut self, json: &[u8]) -> &mut Self {
+        self.buf
+            .extend_from_slice(&(json.len() as i32 + 1).to_be_bytes());
+        self.buf.push(1);
+        self.buf.extend_from_slice(json);
+        self
+    }
+}
+
 pub(crate) struct DidDelete {
-    outpoint: String,
-    consumed_tx: String,
+    outpoint: Vec<u8>,
+    consumed_tx: H256,
     consumed_at: chrono::DateTime<chrono::Utc>,
+    consumed_block_number: BlockNumber,
 }
 
 impl DidDelete {
     pub fn new(
-        outpoint: String,
-        consumed_tx: String,
+        outpoint: Vec<u8>,
+        consumed_tx: H256,
         consumed_at: chrono::DateTime<chrono::Utc>,
+        consumed_block_number: BlockNumber,
     ) -> Self {
         Self {
             outpoint,
             consumed_tx,
             consumed_at,
+            consumed_block_number,
         }
     }
 
+    /// Mark the consumed cells in one statement, returns the DIDs of the indexed cells it
+    /// consumed.
+    ///
+    /// Consumptions of cells that were never indexed, for example cells that were rejected
+    /// or created before their deployment's start block, are recorded as orphans by the
+    /// same statement instead of being dropped.
     pub async fn delete_batch(
         conn: &mut PgConnection,
         deletes: &[DidDelete],
         net: Network,
-    ) -> Result<(), sqlx::Error> {
+    ) -> Result<Vec<String>, sqlx::Error> {
         if deletes.is_empty() {
+            return Ok(Vec::new());
+        }
+        let sql = format!(
+            "WITH consumed AS (SELECT * FROM unnest($1::bytea[], $2::bytea[], $3::timestamptz[], $4::bigint[]) \
+            AS c(outpoint, consumed_tx, consumed_at, consumed_block_number)), \
+            matched AS (UPDATE {did} d SET valid = false, consumed_tx = c.consumed_tx, consumed_at = c.consumed_at, consumed_block_number = c.consumed_block_number \
+            FROM consumed c WHERE d.outpoint = c.outpoint RETURNING d.outpoint, d.did), \
+            orphans AS (INSERT INTO {orphans} (outpoint, consumed_tx, consumed_at, consumed_block_number) \
+            SELECT DISTINCT ON (outpoint) * FROM consumed c WHERE NOT EXISTS (SELECT 1 FROM matched m WHERE m.outpoint = c.outpoint) \
+            ON CONFLICT (outpoint) DO UPDATE SET consumed_tx = EXCLUDED.consumed_tx, consumed_at = EXCLUDED.consumed_at, consumed_block_number = EXCLUDED.consumed_block_number) \
+            SELECT did FROM matched",
+            did = net.did(),
+            orphans = net.orphan_consumptions()
+        );
+
+        sqlx::query_scalar(&sql)
+            .bind(
+                deletes
+                    .iter()
+                    .map(|delete| delete.outpoint.as_slice())
+                    .collect::<Vec<_>>(),
+            )
+            .bind(
+                deletes
+                    .iter()
+                    .map(|delete| delete.consumed_tx.as_bytes())
+                    .collect::<Vec<_>>(),
+            )
+            .bind(
+                deletes
+                    .iter()
+                    .map(|delete| delete.consumed_at)
+                    .collect::<Vec<_>>(),
+            )
+            .bind(
+                deletes
+                    .iter()
+                    .map(|delete| block_number_to_sql(delete.consumed_block_number))
+                    .collect::<Vec<_>>(),
+            )
+            .fetch_all(conn)
+            .await
+    }
+
+    /// Apply the orphan consumptions whose cell has been indexed since, returns the DIDs of
+    /// those cells.
+    pub async fn reconcile_orphans(
+        conn: &mut PgConnection,
+        net: Network,
+    ) -> Result<Vec<String>, sqlx::Error> {
+        let sql = format!(
+            "WITH matched AS (DELETE FROM {orphans} o USING {did} d WHERE o.outpoint = d.outpoint \
+            RETURNING o.outpoint, o.consumed_tx, o.consumed_at, o.consumed_block_number) \
+            UPDATE {did} d SET valid = false, consumed_tx = m.consumed_tx, consumed_at = m.consumed_at, consumed_block_number = m.consumed_block_number \
+            FROM matched m WHERE d.outpoint = m.outpoint RETURNING d.did",
+            orphans = net.orphan_consumptions(),
+            did = net.did()
+        );
+        sqlx::query_scalar(&sql).fetch_all(conn).await
+    }
+}
+
+/// Link every indexed cell of `dids` to the cell it replaced and the cell that replaced it,
+/// that is the cell of the same DID consumed or created by the same transaction.
+async fn link_versions(
+    conn: &mut PgConnection,
+    net: Network,
+    dids: &[String],
+) -> Result<(), sqlx::Error> {
+    if dids.is_empty() {
+        return Ok(());
+    }
+    let sql = format!(
+        "UPDATE {did} d SET prev_outpoint = l.prev_outpoint, next_outpoint = l.next_outpoint \
+        FROM (SELECT c.outpoint, p.outpoint AS prev_outpoint, n.outpoint AS next_outpoint FROM {did} c \
+        LEFT JOIN {did} p ON p.did = c.did AND p.consumed_tx = c.tx_hash \
+        LEFT JOIN {did} n ON n.did = c.did AND n.tx_hash = c.consumed_tx \
+        WHERE c.did = ANY($1)) l \
+        WHERE d.outpoint = l.outpoint \
+        AND (d.prev_outpoint, d.next_outpoint) IS DISTINCT FROM (l.prev_outpoint, l.next_outpoint)",
+        did = net.did()
+    );
+    sqlx::query(&sql).bind(dids).execute(conn).await?;
+    Ok(())
+}
+
+/// Recompute what is derived from the cells of `dids` after some of them were written,
+/// consumed or removed: the links between their versions and their current documents.
+async fn refresh_dids(
+    conn: &mut PgConnection,
+    net: Network,
+    dids: &[String],
+) -> Result<(), sqlx::Error> {
+    link_versions(conn, net, dids).await?;
+    DidCurrent::refresh(conn, net, dids).await
+}
+
+/// The current document of every DID, its latest indexed cell that is not consumed.
+pub(crate) struct DidCurrent;
+
+impl DidCurrent {
+    /// Recompute the current documents of `dids` from `did_documents`, a DID whose cells are
+    /// all consumed has none.
+    ///
+    /// Rows are upserted rather than replaced, so that the monitor and a `reindex` touching
+    /// the same DID at the same time wait for each other instead of failing.
+    pub async fn refresh(
+        conn: &mut PgConnection,
+        net: Network,
+        dids: &[String],
+    ) -> Result<(), sqlx::Error> {
+        if dids.is_empty() {
             return Ok(());
         }
+        // a cell re-indexed under another DID leaves the row of its former DID behind
+        let sql = format!(
+            "DELETE FROM {current} c WHERE (c.did = ANY($1) \
+            AND NOT EXISTS (SELECT 1 FROM {did} d WHERE d.did = c.did AND d.consumed_tx IS NULL)) \
+            OR EXISTS (SELECT 1 FROM {did} d WHERE d.outpoint = c.outpoint AND d.did <> c.did AND d.did = ANY($1))",
+            current = net.current(),
+            did = net.did()
+        );
+        sqlx::query(&sql).bind(dids).execute(&mut *conn).await?;
         let sql = format!(
-            "UPDATE {} SET valid = false, consumed_tx= $2, consumed_at = $3 WHERE outpoint = $1",
+            "INSERT INTO {} ({DID_COLUMNS}, prev_outpoint) SELECT DISTINCT ON (did) {DID_COLUMNS}, prev_outpoint FROM {} \
+            WHERE did = ANY($1) AND consumed_tx IS NULL ORDER BY did, block_number DESC, outpoint DESC \
+            ON CONFLICT (did) DO UPDATE SET handle = EXCLUDED.handle, signing_key = EXCLUDED.signing_key, \
+            ckb_address = EXCLUDED.ckb_address, tx_hash = EXCLUDED.tx_hash, block_number = EXCLUDED.block_number, \
+            outpoint = EXCLUDED.outpoint, did_document = EXCLUDED.did_document, cell_data = EXCLUDED.cell_data, \
+            lock_script_hash = EXCLUDED.lock_script_hash, created_at = EXCLUDED.created_at, \
+            deployment = EXCLUDED.deployment, prev_outpoint = EXCLUDED.prev_outpoint",
+            net.current(),
             net.did()
         );
+        sqlx::query(&sql).bind(dids).execute(&mut *conn).await?;
+        Ok(())
+    }
+}
 
-        for delete in deletes {
-            sqlx::query(&sql)
-                .bind(&delete.outpoint)
-                .bind(&delete.consumed_tx)
-                .bind(&delete.consumed_at)
-                .execute(&mut *conn)
-                .await?;
+/// A DID cell created by a transaction that is still in the pool.
+pub(crate) struct DidPending {
+    did: String,
+    handle: String,
+    signing_key: String,
+    ckb_address: String,
+    tx_hash: H256,
+    outpoint: Vec<u8>,
+    did_document: Json<Web5DocumentData>,
+    cell_data: Vec<u8>,
+    lock_script_hash: H160,
+    deployment: String,
+}
+
+impl DidPending {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        did: String,
+        handle: String,
+        signing_key: String,
+        ckb_address: String,
+        tx_hash: H256,
+        outpoint: Vec<u8>,
+        did_document: Json<Web5DocumentData>,
+        cell_data: Vec<u8>,
+        lock_script_hash: H160,
+        deployment: String,
+    ) -> Self {
+        Self {
+            did,
+            handle,
+            signing_key,
+            ckb_address,
+            tx_hash,
+            outpoint,
+            did_document,
+            cell_data,
+            lock_script_hash,
+            deployment,
         }
+    }
 
+    pub async fn insert_batch(
+        pool: &Pool<Postgres>,
+        pending: &[DidPending],
+        net: Network,
+    ) -> Result<(), sqlx::Error> {
+        if pending.is_empty() {
+            return Ok(());
+        }
+        for chunk in pending.chunks(BIND_LIMIT / 11) {
+            let sql = format!(
+                "INSERT INTO {} (did, handle, signing_key, ckb_address, tx_hash, outpoint, did_document, cell_data, lock_script_hash, deployment, tx_status) ",
+                net.pending()
+            );
+            let mut query_builder: QueryBuilder<'_, sqlx::Postgres> = QueryBuilder::new(sql);
+            query_builder.push_values(chunk, |mut b, did_pending| {
+                b.push_bind(&did_pending.did)
+                    .push_bind(&did_pending.handle)
+                    .push_bind(&did_pending.signing_key)
+                    .push_bind(&did_pending.ckb_address)
+                    .push_bind(did_pending.tx_hash.as_bytes())
+                    .push_bind(&did_pending.outpoint)
+                    .push_bind(&did_pending.did_document)
+                    .push_bind(&did_pending.cell_data)
+                    .push_bind(did_pending.lock_script_hash.as_bytes())
+                    .push_bind(&did_pending.deployment)
+                    .push_bind("pending");
+            });
+            query_builder.push(" ON CONFLICT (outpoint) DO NOTHING");
+            query_builder.build().execute(pool).await?;
+        }
         Ok(())
     }
+
+    /// Every pending transaction with the time it was first seen.
+    pub async fn fetch_txs(
+        pool: &Pool<Postgres>,
+        net: Network,
+    ) -> Result<Vec<(H256, chrono::DateTime<chrono::Utc>)>, sqlx::Error> {
+        let sql = format!(
+            "SELECT tx_hash, min(created_at) AS created_at FROM {} GROUP BY tx_hash",
+            net.pending()
+        );
+        let rows = sqlx::query(&sql).fetch_all(pool).await?;
+        Ok(rows
+            .iter()
+            .filter_map(|row| {
+                let hash = hash_from_sql(row.get("tx_hash"))?;
+                Some((hash, row.get("created_at")))
+            })
+            .collect())
+    }
+
+    pub async fn update_status(
+        pool: &Pool<Postgres>,
+        net: Network,
+        tx_hash: &H256,
+        tx_status: &str,
+    ) -> Result<(), sqlx::Error> {
+        let sql = format!(
+            "UPDATE {} SET tx_status = $2 WHERE tx_hash = $1",
+            net.pending()
+        );
+        sqlx::query(&sql)
+            .bind(tx_hash.as_bytes())
+            .bind(tx_status)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Forget a transaction that left the pool without being committed.
+    pub async fn drop_tx(
+        pool: &Pool<Postgres>,
+        net: Network,
+        tx_hash: &H256,
+    ) -> Result<(), sqlx::Error> {
+        let sql = format!("DELETE FROM {} WHERE tx_hash = $1", net.pending());
+        sqlx::query(&sql)
+            .bind(tx_hash.as_bytes())
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Drop the pending cells that have been indexed from a block since, returns how many.
+    pub async fn promote(pool: &Pool<Postgres>, net: Network) -> Result<u64, sqlx::Error> {
+        let sql = format!(
+            "DELETE FROM {} p USING {} d WHERE p.outpoint = d.outpoint",
+            net.pending(),
+            net.did()
+        );
+        Ok(sqlx::query(&sql).execute(pool).await?.rows_affected())
+    }
+}
+
+/// A DID cell the indexer refused, kept so that its owner can find out why.
+pub(crate) struct DidRejected {
+    tx_hash: H256,
+    output_index: i32,
+    block_number: BlockNumber,
+    did: Option<String>,
+    cell_data: Option<Vec<u8>>,
+    reason: &'static str,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl DidRejected {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        tx_hash: H256,
+        output_index: u32,
+        block_number: BlockNumber,
+        did: Option<String>,
+        cell_data: Option<Vec<u8>>,
+        reason: RejectReason,
+        created_at: chrono::DateTime<chrono::Utc>,
+    ) -> Self {
+        Self {
+            tx_hash,
+            output_index: output_index as i32,
+            block_number,
+            did,
+            cell_data,
+            reason: reason.code(),
+            created_at,
+        }
+    }
+
+    pub async fn insert_batch(
+        conn: &mut PgConnection,
+        rejected: &[DidRejected],
+        net: Network,
+    ) -> Result<(), sqlx::Error> {
+        if rejected.is_empty() {
+            return Ok(());
+        }
+        for chunk in rejected.chunks(BIND_LIMIT / 7) {
+            let sql = format!(
+                "INSERT INTO {} (tx_hash, output_index, block_number, did, cell_data, reason, created_at) ",
+                net.rejected()
+            );
+            let mut query_builder: QueryBuilder<'_, sqlx::Postgres> = QueryBuilder::new(sql);
+            query_builder.push_values(chunk, |mut b, did_rejected| {
+                b.push_bind(did_rejected.tx_hash.as_bytes())
+                    .push_bind(did_rejected.output_index)
+                    .push_bind(block_number_to_sql(did_rejected.block_number))
+                    .push_bind(&did_rejected.did)
+                    .push_bind(&did_rejected.cell_data)
+                    .push_bind(did_rejected.reason)
+                    .push_bind(did_rejected.created_at);
+            });
+            query_builder.push(
+                " ON CONFLICT (tx_hash, output_index) DO UPDATE SET block_number = EXCLUDED.block_number, reason = EXCLUDED.reason, created_at = EXCLUDED.created_at",
+            );
+            query_builder.build().execute(&mut *conn).await?;
+        }
+        Ok(())
+    }
+}
+
+/// A processed block identified by number and hash, used to detect chain reorganizations.
+pub(crate) struct BlockCheckpoint {
+    block_number: BlockNumber,
+    block_hash: H256,
+}
+
+impl BlockCheckpoint {
+    pub fn new(block_number: BlockNumber, block_hash: &H256) -> Self {
+        Self {
+            block_number,
+            block_hash: block_hash.clone(),
+        }
+    }
+
+    pub async fn insert_batch(
+        conn: &mut PgConnection,
+        checkpoints: &[BlockCheckpoint],
+        net: Network,
+    ) -> Result<(), sqlx::Error> {
+        if checkpoints.is_empty() {
+            return Ok(());
+        }
+        for chunk in checkpoints.chunks(BIND_LIMIT / 2) {
+            let sql = format!(
+                "INSERT INTO {} (block_number, block_hash) ",
+                net.checkpoint()
+            );
+            let mut query_builder: QueryBuilder<'_, sqlx::Postgres> = QueryBuilder::new(sql);
+            query_builder.push_values(chunk, |mut b, checkpoint| {
+                b.push_bind(block_number_to_sql(checkpoint.block_number))
+                    .push_bind(checkpoint.block_hash.as_bytes());
+            });
+            query_builder
+                .push(" ON CONFLICT (block_number) DO UPDATE SET block_hash = EXCLUDED.block_hash");
+            query_builder.build().execute(&mut *conn).await?;
+        }
+        Ok(())
+    }
+
+    /// Most recent checkpoints, highest block number first.
+    pub async fn fetch_latest(
+        pool: &Pool<Postgres>,
+        net: Network,
+        limit: i64,
+    ) -> Result<Vec<(BlockNumber, H256)>, sqlx::Error> {
+        let sql = format!(
+            "SELECT block_number, block_hash FROM {} ORDER BY block_number DESC LIMIT $1",
+            net.checkpoint()
+        );
+        let rows = sqlx::query(&sql).bind(limit).fetch_all(pool).await?;
+        Ok(rows
+            .iter()
+            .filter_map(|row| {
+                let number = block_number_from_sql(row.get("block_number"));
+                let hash = hash_from_sql(row.get("block_hash"))?;
+                Some((number, hash))
+            })
+            .collect())
+    }
+
+    /// Drop checkpoints below `number`, they are too deep to be reorganized.
+    pub async fn prune(
+        conn: &mut PgConnection,
+        net: Network,
+        number: BlockNumber,
+    ) -> Result<(), sqlx::Error> {
+        let sql = format!("DELETE FROM {} WHERE block_number < $1", net.checkpoint());
+        sqlx::query(&sql)
+            .bind(block_number_to_sql(number))
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Where the indexer resumes for a network: every block below `block_number` is indexed.
+pub struct IndexerState {
+    pub block_number: BlockNumber,
+    pub block_hash: Option<H256>,
+    /// Chain tip seen by the last indexing run, blocks between the two are not confirmed yet.
+    pub tip_block_number: Option<BlockNumber>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl IndexerState {
+    pub async fn fetch(
+        pool: &Pool<Postgres>,
+        net: Network,
+    ) -> Result<Option<IndexerState>, sqlx::Error> {
+        let row = sqlx::query(
+            "SELECT block_number, block_hash, tip_block_number, updated_at FROM indexer_state WHERE network = $1",
+        )
+        .bind(net.name())
+        .fetch_optional(pool)
+        .await?;
+        Ok(row.map(|row| IndexerState {
+            block_number: block_number_from_sql(row.get("block_number")),
+            block_hash: row
+                .get::<Option<&[u8]>, _>("block_hash")
+                .and_then(hash_from_sql),
+            tip_block_number: row
+                .get::<Option<i64>, _>("tip_block_number")
+                .map(block_number_from_sql),
+            updated_at: row.get("updated_at"),
+        }))
+    }
+
+    pub async fn store(
+        conn: &mut PgConnection,
+        net: Network,
+        block_number: BlockNumber,
+        block_hash: Option<&H256>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO indexer_state (network, block_number, block_hash, updated_at) VALUES ($1, $2, $3, now()) \
+            ON CONFLICT (network) DO UPDATE SET block_number = EXCLUDED.block_number, block_hash = EXCLUDED.block_hash, updated_at = EXCLUDED.updated_at",
+        )
+        .bind(net.name())
+        .bind(block_number_to_sql(block_number))
+        .bind(block_hash.map(|hash| hash.as_bytes()))
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Move the checkpoint from `from` to `tip`, returns `false` without writing anything
+    /// when it is not at `from` anymore, for example after `set-state` rewound it while the
+    /// window was being fetched.
+    pub async fn advance(
+        conn: &mut PgConnection,
+        net: Network,
+        from: BlockNumber,
+        tip: &IndexerTip,
+    ) -> Result<bool, sqlx::Error> {
+        let res = sqlx::query(
+            "INSERT INTO indexer_state (network, block_number, block_hash, updated_at) VALUES ($1, $2, $3, now()) \
+            ON CONFLICT (network) DO UPDATE SET block_number = EXCLUDED.block_number, block_hash = EXCLUDED.block_hash, updated_at = EXCLUDED.updated_at \
+            WHERE indexer_state.block_number = $4",
+        )
+        .bind(net.name())
+        .bind(block_number_to_sql(tip.block_number))
+        .bind(tip.block_hash.as_bytes())
+        .bind(block_number_to_sql(from))
+        .execute(conn)
+        .await?;
+        Ok(res.rows_affected() == 1)
+    }
+
+    /// Record the chain tip seen by an indexing run without moving the checkpoint.
+    pub async fn store_tip(
+        pool: &Pool<Postgres>,
+        net: Network,
+        tip_block_number: BlockNumber,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO indexer_state (network, block_number, tip_block_number, updated_at) VALUES ($1, $2, $3, now()) \
+            ON CONFLICT (network) DO UPDATE SET tip_block_number = EXCLUDED.tip_block_number",
+        )
+        .bind(net.name())
+        .bind(block_number_to_sql(**global_cache_of(net).load()))
+        .bind(block_number_to_sql(tip_block_number))
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Undo everything indexed at or above `fork_number`: rows created there are removed,
+/// consumptions made there are reverted, checkpoints above it are forgotten and the version
+/// links and current documents of the DIDs involved are recomputed.
+pub(crate) async fn rollback(
+    conn: &mut PgConnection,
+    net: Network,
+    fork_number: BlockNumber,
+) -> Result<(), sqlx::Error> {
+    let fork_number = block_number_to_sql(fork_number);
+
+    let sql = format!(
+        "SELECT DISTINCT did FROM {} WHERE block_number >= $1 OR consumed_block_number >= $1",
+        net.did()
+    );
+    let dids: Vec<String> = sqlx::query_scalar(&sql)
+        .bind(fork_number)
+        .fetch_all(&mut *conn)
+        .await?;
+
+    let sql = format!("DELETE FROM {} WHERE block_number >= $1", net.did());
+    sqlx::query(&sql)
+        .bind(fork_number)
+        .execute(&mut *conn)
+        .await?;
+
+    let sql = format!(
+        "UPDATE {} SET valid = true, consumed_tx = NULL, consumed_at = NULL, consumed_block_number = NULL WHERE consumed_block_number >= $1",
+        net.did()
+    );
+    sqlx::query(&sql)
+        .bind(fork_number)
+        .execute(&mut *conn)
+        .await?;
+
+    let sql = format!("DELETE FROM {} WHERE block_number >= $1", net.rejected());
+    sqlx::query(&sql)
+        .bind(fork_number)
+        .execute(&mut *conn)
+        .await?;
+
+    let sql = format!(
+        "DELETE FROM {} WHERE consumed_block_number >= $1",
+        net.orphan_consumptions()
+    );
+    sqlx::query(&sql)
+        .bind(fork_number)
+        .execute(&mut *conn)
+        .await?;
+
+    let sql = format!("DELETE FROM {} WHERE block_number > $1", net.checkpoint());
+    sqlx::query(&sql)
+        .bind(fork_number)
+        .execute(&mut *conn)
+        .await?;
+    refresh_dids(conn, net, &dids).await
+}
+
+/// Move the indexer checkpoint of `net` to `block_number`.
+///
+/// Rewinding rolls back everything indexed at or above the new checkpoint so that the
+/// range is indexed again from scratch; moving forward skips the blocks in between.
+pub async fn set_indexer_state(
+    pool: &Pool<Postgres>,
+    net: Network,
+    block_number: BlockNumber,
+) -> Result<(), sqlx::Error> {
+    let mut conn = pool.begin().await?;
+    // the state row is locked first, a window committing at the same time either lands
+    // before the rollback or finds the state moved and writes nothing
+    IndexerState::store(&mut conn, net, block_number, None).await?;
+    rollback(&mut conn, net, block_number).await?;
+    conn.commit().await?;
+    global_cache_of(net).store(Arc::new(block_number));
+    Ok(())
+}
+
+/// Rewrite what was indexed from `[start, end)`, in a single transaction, returns the number
+/// of orphan consumptions in the range.
+///
+/// Rows are upserted, rows of the range that are not in `dids` anymore are removed,
+/// consumptions of the range are reverted and applied again from `deletes`, and rejections and orphan consumptions of the range are
+/// replaced. Orphan consumptions of cells indexed now are applied, and the version links
+/// and current documents of every DID involved are recomputed. Checkpoints and the indexer
+/// state are left alone.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn reindex_block_range(
+    pool: &Pool<Postgres>,
+    net: Network,
+    start: BlockNumber,
+    end: BlockNumber,
+    dids: &[DidWrite],
+    deletes: &[DidDelete],
+    rejected: &[DidRejected],
+) -> Result<u64, sqlx::Error> {
+    let mut conn = pool.begin().await?;
+    let sql = format!(
+        "DELETE FROM {} WHERE block_number >= $1 AND block_number < $2",
+        net.rejected()
+    );
+    sqlx::query(&sql)
+        .bind(block_number_to_sql(start))
+        .bind(block_number_to_sql(end))
+        .execute(&mut *conn)
+        .await?;
+    let sql = format!(
+        "DELETE FROM {} WHERE consumed_block_number >= $1 AND consumed_block_number < $2",
+        net.orphan_consumptions()
+    );
+    sqlx::query(&sql)
+        .bind(block_number_to_sql(start))
+        .bind(block_number_to_sql(end))
+        .execute(&mut *conn)
+        .await?;
+    DidWrite::insert_batch(&mut conn, dids, net).await?;
+    let mut changed: Vec<String> = dids.iter().map(|did| did.did.clone()).collect();
+    // consumptions made in the range are reverted and applied again from `deletes`, a
+    // phantom one does not survive
+    let sql = format!(
+        "UPDATE {} SET valid = true, consumed_tx = NULL, consumed_at = NULL, consumed_block_number = NULL \
+        WHERE consumed_block_number >= $1 AND consumed_block_number < $2 RETURNING did",
+        net.did()
+    );
+    let unconsumed: Vec<String> = sqlx::query_scalar(&sql)
+        .bind(block_number_to_sql(start))
+        .bind(block_number_to_sql(end))
+        .fetch_all(&mut *conn)
+        .await?;
+    changed.extend(unconsumed);
+    // rows of the range the fresh fetch no longer produces, such as cells that are rejected
+    // now or phantoms from a bad node, are removed and their consumptions kept as orphans
+    let sql = format!(
+        "WITH removed AS (DELETE FROM {} WHERE block_number >= $1 AND block_number < $2 AND NOT (outpoint = ANY($3)) \
+        RETURNING did, outpoint, consumed_tx, consumed_at, consumed_block_number), \
+        orphans AS (INSERT INTO {} (outpoint, consumed_tx, consumed_at, consumed_block_number) \
+        SELECT outpoint, consumed_tx, consumed_at, consumed_block_number FROM removed \
+        WHERE consumed_tx IS NOT NULL ON CONFLICT (outpoint) DO NOTHING) \
+        SELECT did FROM removed",
+        net.did(),
+        net.orphan_consumptions()
+    );
+    let removed: Vec<String> = sqlx::query_scalar(&sql)
+        .bind(block_number_to_sql(start))
+        .bind(block_number_to_sql(end))
+        .bind(
+            dids.iter()
+                .map(|did| did.outpoint.as_slice())
+                .collect::<Vec<_>>(),
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+    if !removed.is_empty() {
+        log::warn!(
+            "{:?} Removed {} indexed DID cells from block number {} to {} that are not produced anymore",
+            net,
+            removed.len(),
+            start.value(),
+            end.value()
+        );
+    }
+    changed.extend(removed);
+    changed.extend(DidDelete::reconcile_orphans(&mut conn, net).await?);
+    let consumed = DidDelete::delete_batch(&mut conn, deletes, net).await?;
+    let orphans = (deletes.len() - consumed.len()) as u64;
+    changed.extend(consumed);
+    refresh_dids(&mut conn, net, &changed).await?;
+    DidRejected::insert_batch(&mut conn, rejected, net).await?;
+    conn.commit().await?;
+    Ok(orphans)
+}
+
+/// Write everything indexed from `start` to `tip` in a single transaction: new cells,
+/// consumed cells, the version links and current documents of their DIDs, rejected cells,
+/// block checkpoints and the indexer state either all land or none of them do. Returns the
+/// number of orphan consumptions, see [`DidDelete::delete_batch`], or `None` when the
+/// indexer state is not at `start` anymore and nothing was written.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn commit_block_range(
+    pool: &Pool<Postgres>,
+    net: Network,
+    start: BlockNumber,
+    dids: &[DidWrite],
+    deletes: &[DidDelete],
+    rejected: &[DidRejected],
+    checkpoints: &[BlockCheckpoint],
+    tip: &IndexerTip,
+) -> Result<Option<u64>, sqlx::Error> {
+    let mut conn = pool.begin().await?;
+    if !IndexerState::advance(&mut conn, net, start, tip).await? {
+        return Ok(None);
+    }
+    DidWrite::insert_batch(&mut conn, dids, net).await?;
+    let consumed = DidDelete::delete_batch(&mut conn, deletes, net).await?;
+    let orphans = (deletes.len() - consumed.len()) as u64;
+    let changed: Vec<String> = dids
+        .iter()
+        .map(|did| did.did.clone())
+        .chain(consumed)
+        .collect();
+    refresh_dids(&mut conn, net, &changed).await?;
+    DidRejected::insert_batch(&mut conn, rejected, net).await?;
+    BlockCheckpoint::insert_batch(&mut conn, checkpoints, net).await?;
+    BlockCheckpoint::prune(
+        &mut conn,
+        net,
+        tip.block_number
+            .value()
+            .saturating_sub(CHECKPOINT_DEPTH)
+            .into(),
+    )
+    .await?;
+    conn.commit().await?;
+    global_cache_of(net).store(Arc::new(tip.block_number));
+    Ok(Some(orphans))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timestamp(rfc3339: &str) -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::parse_from_rfc3339(rfc3339)
+            .unwrap()
+            .with_timezone(&chrono::Utc)
+    }
+
+    #[test]
+    fn binary_copy_row() {
+        let mut rows = BinaryCopy::new();
+        rows.row(5)
+            .text("ab")
+            .bytes(&[0xde, 0xad])
+            .bigint(-2)
+            .timestamptz(timestamp("2000-01-01T00:00:01Z"))
+            .jsonb(b"{}");
+        let mut expected = Vec::new();
+        // signature, flags, header extension length
+        expected.extend_from_slice(b"PGCOPY\n\xff\r\n\0");
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]);
+        // field count
+        expected.extend_from_slice(&[0, 5]);
+        expected.extend_from_slice(&[0, 0, 0, 2, b'a', b'b']);
+        expected.extend_from_slice(&[0, 0, 0, 2, 0xde, 0xad]);
+        expected.extend_from_slice(&[0, 0, 0, 8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe]);
+        // one second after the Postgres epoch, in microseconds
+        expected.extend_from_slice(&[0, 0, 0, 8, 0, 0, 0, 0, 0, 0x0f, 0x42, 0x40]);
+        // version 1 ahead of the JSON text
+        expected.extend_from_slice(&[0, 0, 0, 3, 1, b'{', b'}']);
+        // trailer
+        expected.extend_from_slice(&[0xff, 0xff]);
+        assert_eq!(rows.finish(), expected);
+    }
+
+    #[test]
+    fn binary_copy_timestamps() {
+        let encoded = |rfc3339: &str| {
+            let mut rows = BinaryCopy::new();
+            let header = rows.len();
+            rows.timestamptz(timestamp(rfc3339));
+            let field = rows.take().split_off(header);
+            assert_eq!(field[..4], [0, 0, 0, 8]);
+            i64::from_be_bytes(field[4..].try_into().unwrap())
+        };
+        assert_eq!(encoded("2000-01-01T00:00:00Z"), 0);
+        assert_eq!(encoded("1999-12-31T23:59:59.999999Z"), -1);
+        // as computed by Postgres from the same timestamp
+        assert_eq!(encoded("2024-03-01T12:34:56.789012Z"), 762_611_696_789_012);
+        assert_eq!(
+            encoded("2024-03-01T14:34:56.789012+02:00"),
+            762_611_696_789_012
+        );
+    }
+
+    #[test]
+    fn binary_copy_take_keeps_rows_apart() {
+        let mut rows = BinaryCopy::new();
+        rows.row(1).bigint(1);
+        let first = rows.take();
+        assert_eq!(rows.len(), 0);
+        rows.row(1).bigint(2);
+        let mut stream = first;
+        stream.extend(rows.finish());
+        let mut single = BinaryCopy::new();
+        single.row(1).bigint(1).row(1).bigint(2);
+        assert_eq!(stream, single.finish());
+    }
 }
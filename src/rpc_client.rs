@@ -1,36 +1,141 @@
 use reqwest::{Client, Url};
 
 use std::{
+    borrow::Cow,
+    fmt,
     future::Future,
-    io,
     str::FromStr,
     sync::{
         Arc, LazyLock,
         atomic::{AtomicU64, Ordering},
     },
+    time::Duration,
 };
 
 use crate::types::{Cell, IndexerTip, Order, Pagination, SearchKey, Tx};
-use ckb_jsonrpc_types::{
-    BlockNumber, HeaderView, JsonBytes, Script, ScriptHashType, TransactionView, TxStatus, Uint32,
-};
+use ckb_jsonrpc_types::{BlockNumber, HeaderView, JsonBytes, TransactionView, TxStatus, Uint32};
 use ckb_sdk::NetworkType;
 use ckb_types::{H256, h256};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
-pub static CKB_MAINNET_RPC: LazyLock<Url> = LazyLock::new(|| {
-    std::env::var("CKB_MAINNET_RPC_URL")
-        .ok()
-        .and_then(|url| Url::parse(&url).ok())
-        .unwrap_or(Url::parse("https://mainnet.ckb.dev").unwrap())
-});
+#[derive(Debug)]
+pub enum RpcError {
+    /// The request never got a response: connection, timeout or HTTP status failures.
+    Transport(reqwest::Error),
+    /// The node answered with a JSON-RPC error object.
+    JsonRpc(jsonrpc_core::Error),
+    /// The response does not have the expected shape.
+    Decode(serde_json::Error),
+    /// The node does not know the requested object.
+    NotFound(String),
+}
 
-pub static CKB_TESTNET_RPC: LazyLock<Url> = LazyLock::new(|| {
-    std::env::var("CKB_TESTNET_RPC_URL")
-        .ok()
-        .and_then(|url| Url::parse(&url).ok())
-        .unwrap_or(Url::parse("https://testnet.ckb.dev").unwrap())
-});
+/// An error that [`RetryPolicy::retry`] can tell transient failures apart with.
+pub trait Retryable: fmt::Display {
+    /// Whether trying again may succeed.
+    fn is_retryable(&self) -> bool;
+}
+
+impl Retryable for RpcError {
+    /// A response that failed to decode, or a request the node rejects as malformed, will
+    /// fail the same way on every attempt.
+    fn is_retryable(&self) -> bool {
+        use jsonrpc_core::ErrorCode;
+        match self {
+            RpcError::Decode(_) => false,
+            RpcError::JsonRpc(e) => !matches!(
+                e.code,
+                ErrorCode::InvalidRequest | ErrorCode::MethodNotFound | ErrorCode::InvalidParams
+            ),
+            RpcError::Transport(_) | RpcError::NotFound(_) => true,
+        }
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Transport(e) => write!(f, "transport error: {}", e),
+            RpcError::JsonRpc(e) => write!(f, "json-rpc error {}: {}", e.code.code(), e.message),
+            RpcError::Decode(e) => write!(f, "decode error: {}", e),
+            RpcError::NotFound(what) => write!(f, "not found: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RpcError::Transport(e) => Some(e),
+            RpcError::JsonRpc(e) => Some(e),
+            RpcError::Decode(e) => Some(e),
+            RpcError::NotFound(_) => None,
+        }
+    }
+}
+
+/// How failed RPC calls and database writes are retried: exponential backoff from
+/// `initial_backoff` up to `max_backoff`, at most `max_attempts` tries in total
+/// (`0` retries forever).
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Call `f` until it succeeds, the error is not retryable or the attempts run out,
+    /// `what` names the call in logs.
+    pub async fn retry<T, E, F, Fut>(&self, what: impl fmt::Display, mut f: F) -> Result<T, E>
+    where
+        E: Retryable,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Ok(res) => return Ok(res),
+                Err(e)
+                    if !e.is_retryable()
+                        || (self.max_attempts != 0 && attempt >= self.max_attempts) =>
+                {
+                    log::error!(
+                        "{} failed after {} attempt(s), giving up: {}",
+                        what,
+                        attempt,
+                        e
+                    );
+                    return Err(e);
+                }
+                Err(e) => {
+                    log::warn!(
+                        "{} failed (attempt {}), retrying in {:?}: {}",
+                        what,
+                        attempt,
+                        backoff,
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = std::cmp::min(backoff.saturating_mul(2), self.max_backoff);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
 
 macro_rules! jsonrpc {
     ($method:expr, $self:ident, $url:expr, $return:ty$(, $params:ident$(,)?)*) => {{
@@ -54,24 +159,32 @@ macro_rules! jsonrpc {
             let resp = c
                 .send()
                 .await
-                .map_err::<io::Error, _>(|e| io::Error::new(io::ErrorKind::ConnectionAborted, format!("{:?}", e)))?;
-            let output = resp
-                .json::<jsonrpc_core::response::Output>()
-                .await
-                .map_err::<io::Error, _>(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+                .and_then(|resp| resp.error_for_status())
+                .map_err(RpcError::Transport)?;
+            let body = resp.bytes().await.map_err(RpcError::Transport)?;
+            let output = serde_json::from_slice::<jsonrpc_core::response::Output>(&body)
+                .map_err(RpcError::Decode)?;
 
             match output {
                 jsonrpc_core::response::Output::Success(success) => {
-                    Ok(serde_json::from_value::<$return>(success.result).unwrap())
+                    serde_json::from_value::<$return>(success.result).map_err(RpcError::Decode)
                 }
-                jsonrpc_core::response::Output::Failure(e) => {
-                    Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))
+                jsonrpc_core::response::Output::Failure(failure) => {
+                    Err(RpcError::JsonRpc(failure.error))
                 }
             }
         }
     }}
 }
 
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
+struct TransactionWithStatusResponse {
+    /// The transaction.
+    pub transaction: Option<TransactionView>,
+    /// The Transaction status.
+    pub tx_status: TxStatus,
+}
+
 // Default implementation of ckb Rpc client
 #[derive(Clone)]
 pub struct RpcClient {
@@ -106,14 +219,7 @@ impl RpcClient {
         &self,
         url: Url,
         hash: &H256,
-    ) -> impl Future<Output = Result<Option<TransactionView>, io::Error>> {
-        #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
-        struct TransactionWithStatusResponse {
-            /// The transaction.
-            pub transaction: Option<TransactionView>,
-            /// The Transaction status.
-            pub tx_status: TxStatus,
-        }
+    ) -> impl Future<Output = Result<Option<TransactionView>, RpcError>> {
         let task = jsonrpc!(
             "get_transaction",
             self,
@@ -127,6 +233,28 @@ impl RpcClient {
         }
     }
 
+    /// Where the transaction is: in the pool, committed, rejected or unknown to the node.
+    pub fn get_transaction_status(
+        &self,
+        url: Url,
+        hash: &H256,
+    ) -> impl Future<Output = Result<TxStatus, RpcError>> {
+        // verbosity 1 leaves the transaction itself out of the response
+        let verbosity = Uint32::from(1);
+        let task = jsonrpc!(
+            "get_transaction",
+            self,
+            url,
+            TransactionWithStatusResponse,
+            hash,
+            verbosity
+        );
+        async {
+            let res = task.await?;
+            Ok(res.tx_status)
+        }
+    }
+
     pub fn get_transactions(
         &self,
         url: Url,
@@ -134,7 +262,7 @@ impl RpcClient {
         order: Order,
         limit: Uint32,
         after: Option<JsonBytes>,
-    ) -> impl Future<Output = Result<Pagination<Tx>, io::Error>> {
+    ) -> impl Future<Output = Result<Pagination<Tx>, RpcError>> {
         jsonrpc!(
             "get_transactions",
             self,
@@ -154,7 +282,7 @@ impl RpcClient {
         order: Order,
         limit: Uint32,
         after: Option<JsonBytes>,
-    ) -> impl Future<Output = Result<Pagination<Cell>, io::Error>> {
+    ) -> impl Future<Output = Result<Pagination<Cell>, RpcError>> {
         jsonrpc!(
             "get_cells",
             self,
@@ -167,7 +295,7 @@ impl RpcClient {
         )
     }
 
-    pub fn get_indexer_tip(&self, url: Url) -> impl Future<Output = Result<IndexerTip, io::Error>> {
+    pub fn get_indexer_tip(&self, url: Url) -> impl Future<Output = Result<IndexerTip, RpcError>> {
         jsonrpc!("get_indexer_tip", self, url, IndexerTip)
     }
 
@@ -175,9 +303,138 @@ impl RpcClient {
         &self,
         url: Url,
         number: BlockNumber,
-    ) -> impl Future<Output = Result<HeaderView, io::Error>> {
+    ) -> impl Future<Output = Result<HeaderView, RpcError>> {
         jsonrpc!("get_header_by_number", self, url, HeaderView, number)
     }
+
+    /// `get_transaction` for every hash in one JSON-RPC batch, results are in `hashes` order.
+    pub fn batch_get_transaction(
+        &self,
+        url: Url,
+        hashes: &[H256],
+    ) -> impl Future<Output = Result<Vec<Result<Option<TransactionView>, RpcError>>, RpcError>>
+    {
+        let task = self.batch::<_, TransactionWithStatusResponse>(
+            url,
+            "get_transaction",
+            hashes.iter().map(|hash| (hash,)).collect(),
+        );
+        async {
+            let res = task.await?;
+            Ok(res
+                .into_iter()
+                .map(|item| item.map(|res| res.transaction))
+                .collect())
+        }
+    }
+
+    /// `get_header_by_number` for every number in one JSON-RPC batch, results are in
+    /// `numbers` order.
+    pub fn batch_get_header_by_number(
+        &self,
+        url: Url,
+        numbers: &[BlockNumber],
+    ) -> impl Future<Output = Result<Vec<Result<HeaderView, RpcError>>, RpcError>> {
+        self.batch(
+            url,
+            "get_header_by_number",
+            numbers.iter().map(|number| (number,)).collect(),
+        )
+    }
+
+    /// Send one call of `method` per entry of `params` as a single JSON-RPC batch.
+    ///
+    /// The outer error is for the request as a whole, a failed call only fails its own
+    /// entry. Responses are matched back to their call by id.
+    fn batch<P: Serialize, T: DeserializeOwned>(
+        &self,
+        url: Url,
+        method: &str,
+        params: Vec<P>,
+    ) -> impl Future<Output = Result<Vec<Result<T, RpcError>>, RpcError>> + use<P, T> {
+        let first_id = self.id.fetch_add(params.len() as u64, Ordering::AcqRel);
+        let req_json = params
+            .iter()
+            .enumerate()
+            .map(|(i, params)| {
+                serde_json::json!({
+                    "id": first_id + i as u64,
+                    "jsonrpc": "2.0",
+                    "method": method,
+                    "params": params,
+                })
+            })
+            .collect::<Vec<_>>();
+        let len = params.len();
+
+        let c = self.raw.post(url).json(&req_json);
+        let c = if let Some(token) = &self.bearer_token {
+            c.bearer_auth(token)
+        } else {
+            c
+        };
+        async move {
+            if len == 0 {
+                return Ok(Vec::new());
+            }
+            let resp = c
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status())
+                .map_err(RpcError::Transport)?;
+            let body = resp.bytes().await.map_err(RpcError::Transport)?;
+            match_batch_response(&body, first_id, len)
+        }
+    }
+}
+
+/// Match the calls of a batch with ids `first_id..first_id + len` to the responses in
+/// `body` by id. A call without a response fails on its own, responses with a foreign id
+/// are ignored.
+fn match_batch_response<T: DeserializeOwned>(
+    body: &[u8],
+    first_id: u64,
+    len: usize,
+) -> Result<Vec<Result<T, RpcError>>, RpcError> {
+    let outputs = match serde_json::from_slice::<jsonrpc_core::response::Response>(body)
+        .map_err(RpcError::Decode)?
+    {
+        jsonrpc_core::response::Response::Batch(outputs) => outputs,
+        // a single response to a batch means the batch itself was rejected
+        jsonrpc_core::response::Response::Single(jsonrpc_core::response::Output::Failure(
+            failure,
+        )) => return Err(RpcError::JsonRpc(failure.error)),
+        jsonrpc_core::response::Response::Single(jsonrpc_core::response::Output::Success(_)) => {
+            return Err(RpcError::Decode(serde::de::Error::custom(
+                "expected a batch response",
+            )));
+        }
+    };
+
+    let mut results = (0..len)
+        .map(|_| {
+            Err(RpcError::Decode(serde::de::Error::custom(
+                "missing response in batch",
+            )))
+        })
+        .collect::<Vec<_>>();
+    for output in outputs {
+        let index = match output.id() {
+            jsonrpc_core::Id::Num(id) if (first_id..first_id + len as u64).contains(id) => {
+                (id - first_id) as usize
+            }
+            _ => continue,
+        };
+        results[index] = match output {
+            jsonrpc_core::response::Output::Success(success) => {
+                serde_json::from_value::<T>(success.result).map_err(RpcError::Decode)
+            }
+            jsonrpc_core::response::Output::Failure(failure) => {
+                Err(RpcError::JsonRpc(failure.error))
+            }
+        };
+    }
+    Ok(results)
 }
 
 pub static MAINNET_CODE_HASH: LazyLock<H256> = LazyLock::new(|| {
@@ -216,40 +473,159 @@ pub static TESTNET_CODE_HASH: LazyLock<H256> = LazyLock::new(|| {
         ))
 });
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default)]
+/// A network indexed into its own tables.
+///
+/// Custom networks are configured with `INDEXER_NETWORKS` and carry the name they were
+/// configured with, only names returned by [`crate::IndexerConfig::find_network`] or
+/// [`crate::IndexerConfig::networks`] are valid.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
 pub enum Network {
-    #[serde(alias = "mainnet")]
     #[default]
     Mainnet,
-    #[serde(alias = "testnet")]
     Testnet,
+    Custom(&'static str),
+}
+
+impl Serialize for Network {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for Network {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        crate::config()
+            .find_network(&name)
+            .ok_or_else(|| serde::de::Error::custom(format!("unknown network {}", name)))
+    }
 }
 
 impl From<Network> for NetworkType {
     fn from(net: Network) -> Self {
-        match net {
-            Network::Mainnet => NetworkType::Mainnet,
-            Network::Testnet => NetworkType::Testnet,
-        }
+        crate::config().network(net).address_type
     }
 }
 
 impl Network {
-    pub fn did(&self) -> &str {
+    pub fn name(&self) -> &'static str {
         match self {
-            Network::Mainnet => "did_documents",
-            Network::Testnet => "did_documents_testnet",
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Custom(name) => name,
+        }
+    }
+
+    pub fn did(&self) -> Cow<'static, str> {
+        self.table("did_documents")
+    }
+
+    pub fn current(&self) -> Cow<'static, str> {
+        self.table("did_current")
+    }
+
+    pub fn checkpoint(&self) -> Cow<'static, str> {
+        self.table("block_checkpoints")
+    }
+
+    pub fn pending(&self) -> Cow<'static, str> {
+        self.table("did_pending")
+    }
+
+    pub fn rejected(&self) -> Cow<'static, str> {
+        self.table("did_rejected")
+    }
+
+    pub fn orphan_consumptions(&self) -> Cow<'static, str> {
+        self.table("did_orphan_consumptions")
+    }
+
+    /// Mainnet keeps the bare table names, other networks add their name as a suffix.
+    fn table(&self, base: &'static str) -> Cow<'static, str> {
+        match self {
+            Network::Mainnet => Cow::Borrowed(base),
+            _ => Cow::Owned(format!("{}{}", base, self.table_suffix())),
+        }
+    }
+
+    /// Appended to the base name of every table of this network.
+    pub fn table_suffix(&self) -> Cow<'static, str> {
+        match self {
+            Network::Mainnet => Cow::Borrowed(""),
+            _ => Cow::Owned(format!("_{}", self.name())),
         }
     }
 }
 
-pub fn did_script(net: Network, args: JsonBytes) -> Script {
-    Script {
-        code_hash: match net {
-            Network::Mainnet => MAINNET_CODE_HASH.clone(),
-            Network::Testnet => TESTNET_CODE_HASH.clone(),
-        },
-        hash_type: ScriptHashType::Type,
-        args,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matched(
+        body: &str,
+        first_id: u64,
+        len: usize,
+    ) -> Result<Vec<Result<u64, RpcError>>, RpcError> {
+        match_batch_response(body.as_bytes(), first_id, len)
+    }
+
+    #[test]
+    fn batch_responses_are_matched_by_id() {
+        let body = r#"[
+            {"jsonrpc": "2.0", "id": 12, "result": 2},
+            {"jsonrpc": "2.0", "id": 10, "result": 0},
+            {"jsonrpc": "2.0", "id": 11, "result": 1}
+        ]"#;
+        let results = matched(body, 10, 3).unwrap();
+        let results = results.into_iter().map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(results, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn batch_call_without_response_fails_alone() {
+        let body = r#"[
+            {"jsonrpc": "2.0", "id": 11, "result": 1},
+            {"jsonrpc": "2.0", "id": 7, "result": 7},
+            {"jsonrpc": "2.0", "id": "10", "result": 10}
+        ]"#;
+        let results = matched(body, 10, 2).unwrap();
+        assert!(matches!(results[0], Err(RpcError::Decode(_))));
+        assert_eq!(results[1].as_ref().unwrap(), &1);
+    }
+
+    #[test]
+    fn batch_call_error_fails_alone() {
+        let body = r#"[
+            {"jsonrpc": "2.0", "id": 0, "error": {"code": -32000, "message": "not found"}},
+            {"jsonrpc": "2.0", "id": 1, "result": "0x1"}
+        ]"#;
+        let results = matched(body, 0, 2).unwrap();
+        assert!(matches!(&results[0], Err(RpcError::JsonRpc(e)) if e.message == "not found"));
+        // a result of the wrong shape fails its own entry
+        assert!(matches!(results[1], Err(RpcError::Decode(_))));
+    }
+
+    #[test]
+    fn single_response_to_batch_fails_whole_batch() {
+        let body = r#"{"jsonrpc": "2.0", "id": null, "error": {"code": -32600, "message": "Invalid request"}}"#;
+        assert!(matches!(
+            matched(body, 0, 2),
+            Err(RpcError::JsonRpc(e)) if e.code == jsonrpc_core::ErrorCode::InvalidRequest
+        ));
+        let body = r#"{"jsonrpc": "2.0", "id": 0, "result": 0}"#;
+        assert!(matches!(matched(body, 0, 2), Err(RpcError::Decode(_))));
+        assert!(matches!(matched("<html>", 0, 2), Err(RpcError::Decode(_))));
+    }
+
+    #[test]
+    fn malformed_requests_are_not_retried() {
+        use jsonrpc_core::ErrorCode;
+        let error = |code| RpcError::JsonRpc(jsonrpc_core::Error::new(code));
+        assert!(!error(ErrorCode::InvalidRequest).is_retryable());
+        assert!(!error(ErrorCode::MethodNotFound).is_retryable());
+        assert!(!error(ErrorCode::InvalidParams).is_retryable());
+        assert!(error(ErrorCode::InternalError).is_retryable());
+        assert!(error(ErrorCode::ServerError(-1)).is_retryable());
+        assert!(RpcError::NotFound("header".to_string()).is_retryable());
     }
 }
@@ -0,0 +1,148 @@
+use std::{io, sync::Arc, time::Duration};
+
+use ckb_jsonrpc_types::{HeaderView, PoolTransactionEntry, TransactionView};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::{Notify, mpsc::UnboundedSender},
+};
+
+/// Delay before reconnecting after the subscription connection is lost.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Keep a `new_tip_header` subscription open on a CKB TCP RPC endpoint
+/// (`tcp_listen_address` in `ckb.toml`) and wake `notify` for every new tip.
+///
+/// The connection is re-established forever, so callers should keep polling on a timer
+/// as well in case the endpoint stays down.
+pub async fn subscribe_new_tip(addr: String, notify: Arc<Notify>) {
+    subscribe(&addr, "new_tip_header", |result| {
+        match serde_json::from_str::<HeaderView>(result) {
+            Ok(header) => log::debug!("New tip {} from {}", header.inner.number.value(), addr),
+            Err(e) => log::debug!("Unrecognized notification from {}: {}", addr, e),
+        }
+        notify.notify_one();
+    })
+    .await
+}
+
+/// Keep a `new_transaction` subscription open on a CKB TCP RPC endpoint and send every
+/// transaction entering its pool to `txs`.
+pub async fn subscribe_new_transaction(addr: String, txs: UnboundedSender<TransactionView>) {
+    subscribe(
+        &addr,
+        "new_transaction",
+        |result| match serde_json::from_str::<PoolTransactionEntry>(result) {
+            Ok(entry) => {
+                let _ = txs.send(entry.transaction);
+            }
+            Err(e) => log::debug!("Unrecognized notification from {}: {}", addr, e),
+        },
+    )
+    .await
+}
+
+async fn subscribe(addr: &str, topic: &str, mut on_notification: impl FnMut(&str)) {
+    loop {
+        if let Err(e) = notification_stream(addr, topic, &mut on_notification).await {
+            log::warn!("{} subscription on {} failed: {}", topic, addr, e);
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn notification_stream(
+    addr: &str,
+    topic: &str,
+    on_notification: &mut impl FnMut(&str),
+) -> Result<(), io::Error> {
+    let stream = TcpStream::connect(addr).await?;
+    let (reader, mut writer) = stream.into_split();
+    let request = format!(
+        "{{\"id\": 0, \"jsonrpc\": \"2.0\", \"method\": \"subscribe\", \"params\": [\"{}\"]}}\n",
+        topic
+    );
+    writer.write_all(request.as_bytes()).await?;
+    log::info!("Subscribed to {} on {}", topic, addr);
+
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let message: serde_json::Value = serde_json::from_str(&line)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if let Some(error) = message.get("error") {
+            return Err(io::Error::other(format!("subscribe failed: {}", error)));
+        }
+        // the subscribe response only carries the subscription id
+        let Some(result) = message.pointer("/params/result").and_then(|r| r.as_str()) else {
+            continue;
+        };
+        on_notification(result);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "subscription closed by remote",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_types::core::{EpochNumberWithFraction, HeaderBuilder};
+    use tokio::net::TcpListener;
+
+    /// Accept one subscriber, answer its `subscribe` request, push one `new_tip_header`
+    /// notification and hang up. Returns the request.
+    async fn serve_once(listener: &TcpListener) -> serde_json::Value {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let request = lines.next_line().await.unwrap().unwrap();
+        let request: serde_json::Value = serde_json::from_str(&request).unwrap();
+
+        let header = HeaderBuilder::default()
+            .number(42u64)
+            .epoch(EpochNumberWithFraction::new(0, 42, 1800))
+            .build();
+        let header = HeaderView::from(header);
+        let response = serde_json::json!({"jsonrpc": "2.0", "result": "0x0", "id": request["id"]});
+        let notification = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "subscribe",
+            "params": {
+                "result": serde_json::to_string(&header).unwrap(),
+                "subscription": "0x0",
+            },
+        });
+        writer
+            .write_all(format!("{}\n{}\n", response, notification).as_bytes())
+            .await
+            .unwrap();
+        request
+    }
+
+    #[tokio::test]
+    async fn new_tip_wakes_and_reconnects_after_eof() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let notify = Arc::new(Notify::new());
+        let subscription = tokio::spawn(subscribe_new_tip(addr, notify.clone()));
+
+        let request = serve_once(&listener).await;
+        assert_eq!(request["method"], "subscribe");
+        assert_eq!(request["params"], serde_json::json!(["new_tip_header"]));
+        tokio::time::timeout(Duration::from_secs(5), notify.notified())
+            .await
+            .expect("the notification wakes the indexer");
+
+        // the mock hung up, the subscription comes back after `RECONNECT_DELAY`
+        let timeout = RECONNECT_DELAY + Duration::from_secs(5);
+        let request = tokio::time::timeout(timeout, serve_once(&listener))
+            .await
+            .expect("the subscription reconnects");
+        assert_eq!(request["params"], serde_json::json!(["new_tip_header"]));
+        tokio::time::timeout(Duration::from_secs(5), notify.notified())
+            .await
+            .expect("the notification after reconnecting wakes the indexer");
+        subscription.abort();
+    }
+}
@@ -152,10 +152,10 @@ pub fn parse_didoc_cell(cell_data: &[u8]) -> Option<Web5DocumentData> {
 }
 
 pub fn check_did_doc(doc: &Web5DocumentData) -> Option<(String, String)> {
-    if doc.also_known_as.len() == 0 || !doc.also_known_as[0].starts_with("at://") {
+    if doc.also_known_as.is_empty() || !doc.also_known_as[0].starts_with("at://") {
         return None;
     }
-    if doc.services.len() == 0 {
+    if doc.services.is_empty() {
         return None;
     }
     let handle = doc.also_known_as[0][5..].to_string();